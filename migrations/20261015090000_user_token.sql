@@ -0,0 +1,11 @@
+CREATE TABLE
+    user_token (
+        user_id TEXT NOT NULL,
+        provider TEXT NOT NULL,
+        access_token TEXT NOT NULL,
+        refresh_token TEXT,
+        expires_at DATETIME,
+        updated_at DATETIME NOT NULL,
+        PRIMARY KEY (user_id, provider),
+        FOREIGN KEY (user_id) REFERENCES user(id)
+    );
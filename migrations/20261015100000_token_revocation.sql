@@ -0,0 +1,9 @@
+CREATE TABLE
+    token_revocation (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id TEXT NOT NULL,
+        provider TEXT NOT NULL,
+        succeeded BOOLEAN NOT NULL,
+        error TEXT,
+        attempted_at DATETIME NOT NULL
+    );
@@ -0,0 +1,62 @@
+-- The user table is rebuilt to remove the provider columns. Migrations run in a transaction with the
+-- foreign keys enabled, so the tables referencing it are rebuilt to reference user_new, renaming it
+-- afterwards updates their foreign keys to the new user table
+CREATE TABLE
+    user_new (
+        id TEXT PRIMARY KEY NOT NULL,
+        username TEXT NOT NULL,
+        image_url TEXT
+    );
+
+INSERT INTO user_new (id, username, image_url)
+SELECT id, username, image_url FROM user;
+
+CREATE TABLE
+    user_identity (
+        user_id TEXT NOT NULL,
+        provider TEXT NOT NULL,
+        account_id TEXT NOT NULL,
+        created_at DATETIME NOT NULL,
+        PRIMARY KEY (provider, account_id),
+        UNIQUE(user_id, provider),
+        FOREIGN KEY (user_id) REFERENCES user_new(id)
+    );
+
+INSERT INTO user_identity (user_id, provider, account_id, created_at)
+SELECT id, provider, account_id, CURRENT_TIMESTAMP FROM user;
+
+CREATE TABLE
+    user_session_new (
+        id TEXT PRIMARY KEY NOT NULL,
+        user_id TEXT REFERENCES user_new(id) NOT NULL,
+        created_at DATETIME NOT NULL,
+        expires_at DATETIME NOT NULL,
+        FOREIGN KEY (user_id) REFERENCES user_new(id)
+    );
+
+INSERT INTO user_session_new (id, user_id, created_at, expires_at)
+SELECT id, user_id, created_at, expires_at FROM user_session;
+
+DROP TABLE user_session;
+ALTER TABLE user_session_new RENAME TO user_session;
+
+CREATE TABLE
+    user_token_new (
+        user_id TEXT NOT NULL,
+        provider TEXT NOT NULL,
+        access_token TEXT NOT NULL,
+        refresh_token TEXT,
+        expires_at DATETIME,
+        updated_at DATETIME NOT NULL,
+        PRIMARY KEY (user_id, provider),
+        FOREIGN KEY (user_id) REFERENCES user_new(id)
+    );
+
+INSERT INTO user_token_new (user_id, provider, access_token, refresh_token, expires_at, updated_at)
+SELECT user_id, provider, access_token, refresh_token, expires_at, updated_at FROM user_token;
+
+DROP TABLE user_token;
+ALTER TABLE user_token_new RENAME TO user_token;
+
+DROP TABLE user;
+ALTER TABLE user_new RENAME TO user;
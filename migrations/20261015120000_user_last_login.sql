@@ -0,0 +1 @@
+ALTER TABLE user ADD COLUMN last_login_at DATETIME;
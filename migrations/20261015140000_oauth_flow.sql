@@ -0,0 +1,13 @@
+CREATE TABLE
+    oauth_flow (
+        state TEXT PRIMARY KEY NOT NULL,
+        provider TEXT NOT NULL,
+        pkce_code_verifier TEXT NOT NULL,
+        nonce TEXT NOT NULL,
+        return_to TEXT,
+        browser_id TEXT NOT NULL,
+        created_at DATETIME NOT NULL,
+        expires_at DATETIME NOT NULL
+    );
+
+CREATE INDEX oauth_flow_expires_at ON oauth_flow (expires_at);
@@ -0,0 +1,12 @@
+-- The sessions were looked up by the plaintext cookie value, all of them are invalidated
+DROP TABLE user_session;
+
+CREATE TABLE
+    user_session (
+        id TEXT PRIMARY KEY NOT NULL,
+        user_id TEXT REFERENCES user(id) NOT NULL,
+        token_hash TEXT NOT NULL UNIQUE,
+        created_at DATETIME NOT NULL,
+        expires_at DATETIME NOT NULL,
+        FOREIGN KEY (user_id) REFERENCES user(id)
+    );
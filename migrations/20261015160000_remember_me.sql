@@ -0,0 +1,3 @@
+ALTER TABLE oauth_flow ADD COLUMN remember_me BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE user_session ADD COLUMN remember_me BOOLEAN NOT NULL DEFAULT FALSE;
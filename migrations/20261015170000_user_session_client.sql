@@ -0,0 +1,5 @@
+ALTER TABLE user_session ADD COLUMN user_agent TEXT;
+
+ALTER TABLE user_session ADD COLUMN ip_address TEXT;
+
+ALTER TABLE user_session ADD COLUMN last_seen_at DATETIME;
@@ -0,0 +1 @@
+ALTER TABLE oauth_flow ADD COLUMN link BOOLEAN NOT NULL DEFAULT FALSE;
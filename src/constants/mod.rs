@@ -1,9 +1,38 @@
 use std::time::Duration;
 
 pub const COOKIE_AUTH_SESSION: &str = "auth_session";
-pub const COOKIE_AUTH_CSRF_STATE: &str = "auth_csrf_state";
-pub const COOKIE_AUTH_CODE_VERIFIER: &str = "auth_code_verifier";
+pub const COOKIE_AUTH_BROWSER_ID: &str = "auth_browser_id";
+pub const COOKIE_CSRF_TOKEN: &str = "csrf_token";
 
 //
 pub const COOKIE_THEME: &str = "theme";
-pub const SESSION_DURATION: Duration = Duration::from_millis(1000 * 60 * 60 * 24); // 1 day
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_millis(1000 * 60 * 60 * 24); // 1 day
+pub const SESSION_MAX_LIFETIME: Duration = Duration::from_millis(1000 * 60 * 60 * 24 * 7); // 7 days
+pub const REMEMBER_ME_IDLE_TIMEOUT: Duration = Duration::from_millis(1000 * 60 * 60 * 24 * 30); // 30 days
+pub const REMEMBER_ME_MAX_LIFETIME: Duration = Duration::from_millis(1000 * 60 * 60 * 24 * 90); // 90 days
+pub const REMEMBER_ME_MAX_DAYS: u64 = 365 * 10; // 10 years
+pub const SESSION_EXTEND_INTERVAL: Duration = Duration::from_millis(1000 * 60 * 5); // 5 minutes
+pub const OAUTH_FLOW_DURATION: Duration = Duration::from_millis(1000 * 60 * 5); // 5 minutes
+pub const MAX_USER_AGENT_LENGTH: usize = 512;
+pub const PURGE_INTERVAL: Duration = Duration::from_millis(1000 * 60 * 60); // 1 hour
+pub const SESSION_CACHE_CAPACITY: usize = 10_000;
+pub const SESSION_CACHE_TTL: Duration = Duration::from_millis(1000 * 60); // 1 minute
+pub const CSRF_FORM_FIELD: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+pub const MAX_CSRF_FORM_SIZE: usize = 1024 * 64; // 64 KB
+pub const DEFAULT_IMAGE_PROXY_HOSTS: &[&str] = &[
+    "lh3.googleusercontent.com",
+    "avatars.githubusercontent.com",
+    "cdn.discordapp.com",
+];
+pub const PROVIDER_CONNECT_TIMEOUT: Duration = Duration::from_millis(1000 * 5); // 5 seconds
+pub const PROVIDER_TIMEOUT: Duration = Duration::from_millis(1000 * 10); // 10 seconds
+pub const IMAGE_PROXY_MAX_SIZE: usize = 1024 * 1024 * 5; // 5 MB
+pub const IMAGE_PROXY_CONNECT_TIMEOUT: Duration = Duration::from_millis(1000 * 3); // 3 seconds
+pub const IMAGE_PROXY_TIMEOUT: Duration = Duration::from_millis(1000 * 10); // 10 seconds
+pub const DEFAULT_AVATAR_CACHE_DIR: &str = "./data/avatars";
+pub const AVATAR_SIZES: &[u32] = &[40, 80];
+pub const AVATAR_MAX_SOURCE_SIZE: u32 = 4096; // pixels
+pub const AVATAR_CACHE_CONTROL: &str = "private, max-age=3600";
+pub const AVATAR_FAILURE_TTL: Duration = Duration::from_millis(1000 * 60 * 5); // 5 minutes
+pub const INITIALS_AVATAR_CSP: &str = "default-src 'none'";
@@ -1,6 +1,8 @@
-use std::{str::FromStr, time::Duration};
+use std::time::Duration;
 
-use crate::models::{AuthProvider, User, UserSession};
+use crate::models::{AuthProvider, OAuthFlow, User, UserIdentity, UserSession, UserToken};
+use chrono::NaiveDateTime;
+use sha2::{Digest, Sha256};
 use sqlx::SqlitePool;
 use uuid::Uuid;
 
@@ -13,9 +15,16 @@ pub async fn get_user_by_account_id(
     let user = sqlx::query_as!(
         User,
         r#"
-            SELECT id as "id: uuid::Uuid", account_id, provider, username, image_url
-            FROM user 
-            WHERE account_id = ?1 AND provider = ?2
+            SELECT
+                user.id as "id: uuid::Uuid",
+                username,
+                image_url,
+                email,
+                email_verified,
+                last_login_at as "last_login_at: _"
+            FROM user
+            INNER JOIN user_identity AS identity ON identity.user_id = user.id
+            WHERE identity.account_id = ?1 AND identity.provider = ?2
         "#,
         account_id,
         provider
@@ -26,34 +35,28 @@ pub async fn get_user_by_account_id(
     Ok(user)
 }
 
-pub async fn get_user_by_session_id(
+pub async fn get_user_by_id(
     pool: &SqlitePool,
-    session_id: &str,
+    user_id: Uuid,
 ) -> Result<Option<User>, anyhow::Error> {
-    let session_id = Uuid::from_str(session_id)?;
     let user = sqlx::query_as!(
         User,
         r#"
-            SELECT user.id as "id: uuid::Uuid", account_id, provider, username, image_url
+            SELECT
+                id as "id: uuid::Uuid",
+                username,
+                image_url,
+                email,
+                email_verified,
+                last_login_at as "last_login_at: _"
             FROM user
-            LEFT JOIN user_session AS session ON session.user_id = user.id
-            WHERE session.id = ?1
+            WHERE id = ?1
         "#,
-        session_id
+        user_id
     )
     .fetch_optional(pool)
     .await?;
 
-    if let Some(user) = &user {
-        let deleted = delete_expired_user_sessions(pool, user.id).await?;
-        if deleted > 0 {
-            tracing::info!(
-                "{deleted:?} expired sessions where deleted for user '{}'",
-                user.id
-            )
-        }
-    }
-
     Ok(user)
 }
 
@@ -63,44 +66,203 @@ pub async fn create_user(
     provider: AuthProvider,
     username: String,
     image_url: Option<String>,
+    email: Option<String>,
+    email_verified: bool,
 ) -> Result<User, anyhow::Error> {
     let id = Uuid::new_v4();
     let provider = provider.to_string();
+    let created_at = chrono::offset::Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+
     let new_user = sqlx::query_as!(
         User,
         r#"
-            INSERT INTO user (id, account_id, provider, username, image_url) 
-            VALUES (?1, ?2, ?3, ?4, ?5) 
-            RETURNING id as "id: uuid::Uuid", account_id, provider, username, image_url
+            INSERT INTO user (id, username, image_url, email, email_verified, last_login_at) 
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6) 
+            RETURNING
+                id as "id: uuid::Uuid",
+                username,
+                image_url,
+                email,
+                email_verified,
+                last_login_at as "last_login_at: _"
+        "#,
+        id,
+        username,
+        image_url,
+        email,
+        email_verified,
+        created_at
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+            INSERT INTO user_identity (user_id, provider, account_id, created_at)
+            VALUES (?1, ?2, ?3, ?4)
         "#,
         id,
-        account_id,
         provider,
+        account_id,
+        created_at
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(new_user)
+}
+
+/// Updates the user with the latest profile of the provider and sets the last login time.
+pub async fn update_user_profile(
+    pool: &SqlitePool,
+    user_id: Uuid,
+    username: String,
+    image_url: Option<String>,
+    email: Option<String>,
+    email_verified: bool,
+) -> Result<User, anyhow::Error> {
+    let last_login_at = chrono::offset::Utc::now().naive_utc();
+    let user = sqlx::query_as!(
+        User,
+        r#"
+            UPDATE user
+            SET
+                username = ?2,
+                image_url = ?3,
+                email = ?4,
+                email_verified = ?5,
+                last_login_at = ?6
+            WHERE id = ?1
+            RETURNING
+                id as "id: uuid::Uuid",
+                username,
+                image_url,
+                email,
+                email_verified,
+                last_login_at as "last_login_at: _"
+        "#,
+        user_id,
         username,
-        image_url
+        image_url,
+        email,
+        email_verified,
+        last_login_at
     )
     .fetch_one(pool)
     .await?;
 
-    Ok(new_user)
+    Ok(user)
+}
+
+pub async fn get_user_identities(
+    pool: &SqlitePool,
+    user_id: Uuid,
+) -> Result<Vec<UserIdentity>, anyhow::Error> {
+    let identities = sqlx::query_as!(
+        UserIdentity,
+        r#"
+            SELECT
+                user_id as "user_id: uuid::Uuid",
+                provider,
+                account_id,
+                created_at as "created_at: _"
+            FROM user_identity
+            WHERE user_id = ?1
+            ORDER BY created_at
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(identities)
+}
+
+pub async fn create_user_identity(
+    pool: &SqlitePool,
+    user_id: Uuid,
+    provider: AuthProvider,
+    account_id: String,
+) -> Result<(), anyhow::Error> {
+    let provider = provider.to_string();
+    let created_at = chrono::offset::Utc::now().naive_utc();
+
+    sqlx::query!(
+        r#"
+            INSERT INTO user_identity (user_id, provider, account_id, created_at)
+            VALUES (?1, ?2, ?3, ?4)
+        "#,
+        user_id,
+        provider,
+        account_id,
+        created_at
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
 }
 
+pub async fn delete_user_identity(
+    pool: &SqlitePool,
+    user_id: Uuid,
+    provider: AuthProvider,
+) -> Result<bool, anyhow::Error> {
+    let provider = provider.to_string();
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        "DELETE FROM user_token WHERE user_id = ?1 AND provider = ?2",
+        user_id,
+        provider
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let result = sqlx::query!(
+        "DELETE FROM user_identity WHERE user_id = ?1 AND provider = ?2",
+        user_id,
+        provider
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Creates a session for the user and returns it with the token to send to the client, only the
+/// token hash is stored.
+///
+/// The session expires after the idle timeout, or the max lifetime if it's shorter.
 pub async fn create_user_session(
     pool: &SqlitePool,
     user_id: Uuid,
-    session_duration: Duration,
-) -> Result<UserSession, anyhow::Error> {
+    remember_me: bool,
+    idle_timeout: Duration,
+    max_lifetime: Duration,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+) -> Result<(UserSession, String), anyhow::Error> {
     let session_id = Uuid::new_v4();
+    let session_token = crate::misc::random_hex_token();
+    let token_hash = hash_session_token(&session_token);
     let created_at = chrono::offset::Utc::now().naive_utc();
-    let expires_at = created_at + session_duration;
+    let expires_at = created_at + idle_timeout.min(max_lifetime);
 
     sqlx::query!(
         r#"
-            INSERT INTO user_session (id, user_id, created_at, expires_at)
-            VALUES (?1, ?2, ?3, ?4)
+            INSERT INTO user_session (id, user_id, token_hash, remember_me, user_agent, ip_address, created_at, expires_at, last_seen_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?7)
         "#,
         session_id,
         user_id,
+        token_hash,
+        remember_me,
+        user_agent,
+        ip_address,
         created_at,
         expires_at
     )
@@ -113,8 +275,12 @@ pub async fn create_user_session(
             SELECT 
                 id as "id: uuid::Uuid",
                 user_id as "user_id: uuid::Uuid",
+                remember_me,
+                user_agent,
+                ip_address,
                 created_at as "created_at: _",
-                expires_at as "expires_at: _" 
+                expires_at as "expires_at: _",
+                last_seen_at as "last_seen_at: _"
             FROM user_session
             WHERE id = ?1
         "#,
@@ -123,34 +289,247 @@ pub async fn create_user_session(
     .fetch_one(pool)
     .await?;
 
+    Ok((user_session, session_token))
+}
+
+/// Returns the session of the given token, expired sessions are not returned.
+pub async fn get_user_session(
+    pool: &SqlitePool,
+    session_token: &str,
+) -> Result<Option<UserSession>, anyhow::Error> {
+    let token_hash = hash_session_token(session_token);
+    let now = chrono::offset::Utc::now().naive_utc();
+    let user_session = sqlx::query_as!(
+        UserSession,
+        r#"
+            SELECT
+                id as "id: uuid::Uuid",
+                user_id as "user_id: uuid::Uuid",
+                remember_me,
+                user_agent,
+                ip_address,
+                created_at as "created_at: _",
+                expires_at as "expires_at: _",
+                last_seen_at as "last_seen_at: _"
+            FROM user_session
+            WHERE token_hash = ?1 AND expires_at > ?2
+        "#,
+        token_hash,
+        now
+    )
+    .fetch_optional(pool)
+    .await?;
+
     Ok(user_session)
 }
 
+/// Records the activity of the session and sets its new expiration, returns `false` if the
+/// session no longer exists.
+pub async fn touch_user_session(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    expires_at: NaiveDateTime,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+) -> Result<bool, anyhow::Error> {
+    let last_seen_at = chrono::offset::Utc::now().naive_utc();
+    let result = sqlx::query!(
+        r#"
+            UPDATE user_session
+            SET expires_at = ?2, last_seen_at = ?3, user_agent = ?4, ip_address = ?5
+            WHERE id = ?1
+        "#,
+        session_id,
+        expires_at,
+        last_seen_at,
+        user_agent,
+        ip_address
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Returns the active sessions of the user, the most recently used first.
+pub async fn get_user_sessions(
+    pool: &SqlitePool,
+    user_id: Uuid,
+) -> Result<Vec<UserSession>, anyhow::Error> {
+    let now = chrono::offset::Utc::now().naive_utc();
+    let user_sessions = sqlx::query_as!(
+        UserSession,
+        r#"
+            SELECT
+                id as "id: uuid::Uuid",
+                user_id as "user_id: uuid::Uuid",
+                remember_me,
+                user_agent,
+                ip_address,
+                created_at as "created_at: _",
+                expires_at as "expires_at: _",
+                last_seen_at as "last_seen_at: _"
+            FROM user_session
+            WHERE user_id = ?1 AND expires_at > ?2
+            ORDER BY COALESCE(last_seen_at, created_at) DESC
+        "#,
+        user_id,
+        now
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(user_sessions)
+}
+
 pub async fn delete_user_session(
     pool: &SqlitePool,
-    session_id: &str,
+    session_token: &str,
 ) -> Result<bool, anyhow::Error> {
-    let session_id = Uuid::from_str(session_id)?;
+    let token_hash = hash_session_token(session_token);
     let mut conn = pool.acquire().await?;
 
-    let result = sqlx::query!("DELETE FROM user_session WHERE id = ?1", session_id)
+    let result = sqlx::query!("DELETE FROM user_session WHERE token_hash = ?1", token_hash)
         .execute(&mut *conn)
         .await?;
 
     Ok(result.rows_affected() > 0)
 }
 
-pub async fn delete_expired_user_sessions(
+/// Deletes the session with the given id, only if it belongs to the user.
+pub async fn delete_user_session_by_id(
     pool: &SqlitePool,
     user_id: Uuid,
+    session_id: Uuid,
+) -> Result<bool, anyhow::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM user_session WHERE id = ?1 AND user_id = ?2",
+        session_id,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Deletes all the sessions of the user except the given one.
+pub async fn delete_other_user_sessions(
+    pool: &SqlitePool,
+    user_id: Uuid,
+    session_id: Uuid,
 ) -> Result<usize, anyhow::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM user_session WHERE user_id = ?1 AND id != ?2",
+        user_id,
+        session_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() as usize)
+}
+
+/// Returns the SHA-256 hash of the session token, hex encoded.
+pub fn hash_session_token(session_token: &str) -> String {
+    crate::misc::hex(&Sha256::digest(session_token.as_bytes()))
+}
+
+/// Deletes the expired sessions of all the users.
+pub async fn delete_all_expired_user_sessions(pool: &SqlitePool) -> Result<usize, anyhow::Error> {
     let now = chrono::offset::Utc::now().naive_utc();
     let result = sqlx::query!(
         r#"
-            DELETE FROM user_session 
-            WHERE user_id = ?1 AND ?2 > expires_at
+            DELETE FROM user_session
+            WHERE ?1 > expires_at
+        "#,
+        now
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() as usize)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn create_oauth_flow(
+    pool: &SqlitePool,
+    state: String,
+    provider: AuthProvider,
+    pkce_code_verifier: String,
+    nonce: String,
+    return_to: Option<String>,
+    remember_me: bool,
+    link: bool,
+    browser_id: String,
+    flow_duration: Duration,
+) -> Result<(), anyhow::Error> {
+    let provider = provider.to_string();
+    let created_at = chrono::offset::Utc::now().naive_utc();
+    let expires_at = created_at + flow_duration;
+
+    sqlx::query!(
+        r#"
+            INSERT INTO oauth_flow (state, provider, pkce_code_verifier, nonce, return_to, remember_me, link, browser_id, created_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+        "#,
+        state,
+        provider,
+        pkce_code_verifier,
+        nonce,
+        return_to,
+        remember_me,
+        link,
+        browser_id,
+        created_at,
+        expires_at
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Removes and returns the flow with the given state, so each flow can only be used once.
+///
+/// Expired flows are not returned.
+pub async fn take_oauth_flow(
+    pool: &SqlitePool,
+    state: &str,
+) -> Result<Option<OAuthFlow>, anyhow::Error> {
+    let now = chrono::offset::Utc::now().naive_utc();
+    let oauth_flow = sqlx::query_as!(
+        OAuthFlow,
+        r#"
+            DELETE FROM oauth_flow
+            WHERE state = ?1
+            RETURNING
+                state,
+                provider,
+                pkce_code_verifier,
+                nonce,
+                return_to,
+                remember_me,
+                link,
+                browser_id,
+                created_at as "created_at: _",
+                expires_at as "expires_at: _"
+        "#,
+        state
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(oauth_flow.filter(|x| x.expires_at > now))
+}
+
+pub async fn delete_expired_oauth_flows(pool: &SqlitePool) -> Result<usize, anyhow::Error> {
+    let now = chrono::offset::Utc::now().naive_utc();
+    let result = sqlx::query!(
+        r#"
+            DELETE FROM oauth_flow
+            WHERE ?1 > expires_at
         "#,
-        user_id,
         now
     )
     .execute(pool)
@@ -158,3 +537,133 @@ pub async fn delete_expired_user_sessions(
 
     Ok(result.rows_affected() as usize)
 }
+
+pub async fn get_user_token(
+    pool: &SqlitePool,
+    user_id: Uuid,
+    provider: AuthProvider,
+) -> Result<Option<UserToken>, anyhow::Error> {
+    let provider = provider.to_string();
+    let user_token = sqlx::query_as!(
+        UserToken,
+        r#"
+            SELECT
+                user_id as "user_id: uuid::Uuid",
+                provider,
+                access_token,
+                refresh_token,
+                expires_at as "expires_at: _",
+                updated_at as "updated_at: _"
+            FROM user_token
+            WHERE user_id = ?1 AND provider = ?2
+        "#,
+        user_id,
+        provider
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(user_token)
+}
+
+pub async fn upsert_user_token(
+    pool: &SqlitePool,
+    user_id: Uuid,
+    provider: AuthProvider,
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_at: Option<NaiveDateTime>,
+) -> Result<(), anyhow::Error> {
+    let provider = provider.to_string();
+    let updated_at = chrono::offset::Utc::now().naive_utc();
+
+    // Providers may not return a new refresh token when refreshing, so we keep the previous one
+    sqlx::query!(
+        r#"
+            INSERT INTO user_token (user_id, provider, access_token, refresh_token, expires_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT (user_id, provider) DO UPDATE SET
+                access_token = excluded.access_token,
+                refresh_token = COALESCE(excluded.refresh_token, user_token.refresh_token),
+                expires_at = excluded.expires_at,
+                updated_at = excluded.updated_at
+        "#,
+        user_id,
+        provider,
+        access_token,
+        refresh_token,
+        expires_at,
+        updated_at
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_user_token(
+    pool: &SqlitePool,
+    user_id: Uuid,
+    provider: AuthProvider,
+) -> Result<bool, anyhow::Error> {
+    let provider = provider.to_string();
+    let result = sqlx::query!(
+        "DELETE FROM user_token WHERE user_id = ?1 AND provider = ?2",
+        user_id,
+        provider
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn create_token_revocation(
+    pool: &SqlitePool,
+    user_id: Uuid,
+    provider: AuthProvider,
+    error: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let provider = provider.to_string();
+    let succeeded = error.is_none();
+    let attempted_at = chrono::offset::Utc::now().naive_utc();
+
+    sqlx::query!(
+        r#"
+            INSERT INTO token_revocation (user_id, provider, succeeded, error, attempted_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+        "#,
+        user_id,
+        provider,
+        succeeded,
+        error,
+        attempted_at
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_user(pool: &SqlitePool, user_id: Uuid) -> Result<bool, anyhow::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!("DELETE FROM user_session WHERE user_id = ?1", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!("DELETE FROM user_token WHERE user_id = ?1", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!("DELETE FROM user_identity WHERE user_id = ?1", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let result = sqlx::query!("DELETE FROM user WHERE id = ?1", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(result.rows_affected() > 0)
+}
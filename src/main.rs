@@ -4,15 +4,19 @@ mod misc;
 mod models;
 mod routes;
 mod server;
+mod tasks;
 
 use anyhow::Context;
 use axum::{middleware, Extension, Router};
 use dotenvy::dotenv;
 use sqlx::sqlite::SqlitePool;
 use std::error::Error;
+use std::net::SocketAddr;
 use tower_http::{services::ServeDir, trace::TraceLayer};
 use tracing::Level;
 
+use crate::constants::{SESSION_CACHE_CAPACITY, SESSION_CACHE_TTL};
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     dotenv().ok();
@@ -27,14 +31,25 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .await
         .context("Failed to connect to database")?;
 
+    // Background tasks, stopped on shutdown
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let purge_interval = crate::tasks::purge_interval_from_env()?;
+    let purge_task = crate::tasks::spawn_purge_task(pool.clone(), purge_interval, shutdown_rx);
+
     // Routes
-    let app = Router::new()
-        .merge(public_dir())
-        .merge(crate::routes::api_router())
-        .merge(crate::routes::pages_router())
-        .layer(Extension(pool))
-        .layer(TraceLayer::new_for_http())
-        .layer(middleware::from_fn(crate::routes::error_handler_middleware));
+    let providers = crate::routes::ProviderRegistry::new()?;
+    let cookie_keys = crate::server::CookieKeys::from_env()?;
+    let session_config = crate::server::SessionConfig::from_env()?;
+    let image_proxy = crate::routes::ImageProxy::from_env()?;
+    let avatar_cache = crate::routes::AvatarCache::from_env(image_proxy.clone())?;
+    let app = app(
+        pool,
+        providers,
+        cookie_keys,
+        session_config,
+        image_proxy,
+        avatar_cache,
+    );
 
     // Start server
     let host = std::env::var("HOST").context("'HOST' no found")?;
@@ -50,13 +65,77 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .context("Failed to start tcp listener")?;
 
     println!("Listening on: http://{host}:{port}");
+    // The client address is recorded in the user sessions
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
     axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
         .await
         .context("Failed to start server")?;
 
+    shutdown_tx.send(true).ok();
+    purge_task.await.ok();
+
     Ok(())
 }
 
+fn app(
+    pool: SqlitePool,
+    providers: crate::routes::ProviderRegistry,
+    cookie_keys: crate::server::CookieKeys,
+    session_config: crate::server::SessionConfig,
+    image_proxy: crate::routes::ImageProxy,
+    avatar_cache: crate::routes::AvatarCache,
+) -> Router {
+    let provider_tokens = crate::routes::ProviderTokens::new(providers.http_client().clone());
+    let session_cache = crate::server::SessionCache::new(SESSION_CACHE_CAPACITY, SESSION_CACHE_TTL);
+
+    Router::new()
+        .merge(public_dir())
+        .merge(crate::routes::api_router(&providers))
+        .merge(crate::routes::pages_router())
+        .layer(middleware::from_fn(crate::server::session_middleware))
+        .layer(Extension(pool))
+        .layer(Extension(session_cache))
+        .layer(Extension(provider_tokens))
+        .layer(Extension(image_proxy))
+        .layer(Extension(avatar_cache))
+        .layer(Extension(session_config))
+        .layer(TraceLayer::new_for_http())
+        .layer(middleware::from_fn(crate::routes::error_handler_middleware))
+        // The error handler also needs the providers to render the login page
+        .layer(Extension(providers))
+        // The pages rendered by the error handler also need the csrf token
+        .layer(middleware::from_fn(crate::server::csrf_middleware))
+        .layer(Extension(cookie_keys))
+}
+
 fn public_dir() -> Router {
     Router::new().nest_service("/public", ServeDir::new("public"))
 }
+
+/// Completes when the process receives Ctrl+C or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl+C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("Shutting down");
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::OwnedMutexGuard;
+
+type Locks<K> = HashMap<K, Arc<tokio::sync::Mutex<()>>>;
+
+/// Async locks identified by a key, the tasks locking the same key run one at a time.
+///
+/// The locks are created on first use and removed once no task is holding or waiting for them.
+pub struct KeyedLocks<K> {
+    locks: Arc<Mutex<Locks<K>>>,
+}
+
+impl<K: Eq + Hash> KeyedLocks<K> {
+    /// Waits until no other task holds the lock of the key, the lock is released when the guard
+    /// is dropped.
+    pub async fn lock(&self, key: K) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = lock_unpoisoned(&self.locks);
+
+            // Removes the locks no task is holding or waiting for
+            locks.retain(|_, x| Arc::strong_count(x) > 1);
+
+            locks.entry(key).or_default().clone()
+        };
+
+        lock.lock_owned().await
+    }
+}
+
+impl<K> Default for KeyedLocks<K> {
+    fn default() -> Self {
+        KeyedLocks {
+            locks: Default::default(),
+        }
+    }
+}
+
+impl<K> Clone for KeyedLocks<K> {
+    fn clone(&self) -> Self {
+        KeyedLocks {
+            locks: self.locks.clone(),
+        }
+    }
+}
+
+/// Locks the mutex even if another thread panicked while holding it.
+///
+/// The state behind our mutexes is always left consistent, so a poisoned lock is still usable.
+pub fn lock_unpoisoned<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn same_keys_are_locked_one_at_a_time() {
+        let locks = KeyedLocks::default();
+
+        let guard = locks.lock(1).await;
+        let other_key = tokio::time::timeout(Duration::from_millis(50), locks.lock(2)).await;
+        assert!(other_key.is_ok());
+
+        let same_key = tokio::time::timeout(Duration::from_millis(50), locks.lock(1)).await;
+        assert!(same_key.is_err());
+
+        drop(guard);
+        let same_key = tokio::time::timeout(Duration::from_millis(50), locks.lock(1)).await;
+        assert!(same_key.is_ok());
+    }
+
+    #[tokio::test]
+    async fn released_locks_are_removed() {
+        let locks = KeyedLocks::default();
+
+        for key in 0..10 {
+            drop(locks.lock(key).await);
+        }
+
+        let _guard = locks.lock(10).await;
+        assert_eq!(lock_unpoisoned(&locks.locks).len(), 1);
+    }
+}
@@ -1,5 +1,10 @@
 pub mod error;
-use axum::http::StatusCode;
+mod keyed_locks;
+
+pub use keyed_locks::{KeyedLocks, lock_unpoisoned};
+
+use axum::http::{HeaderMap, StatusCode, header};
+use oauth2::url::Url;
 use std::fmt::Display;
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
@@ -23,3 +28,162 @@ pub struct PageError {
     pub status: StatusCode,
     pub message: String,
 }
+
+/// Returns whether the client accepts HTML, which is the case of the browser navigations and
+/// form submissions but not of the API clients.
+pub fn accepts_html(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .any(|x| x.contains("text/html"))
+}
+
+/// Returns the location to redirect to if the target is on this site.
+///
+/// The target can be a path like `/account?tab=1` or an absolute url with the origin of
+/// `BASE_URL`, which is turned into a path. Protocol relative (`//host`) and backslash paths are
+/// rejected, browsers treat those as other origins.
+pub fn safe_redirect_target(target: &str) -> Option<String> {
+    let base_url = std::env::var("BASE_URL").ok();
+    redirect_target_on(target, base_url.as_deref())
+}
+
+fn redirect_target_on(target: &str, base_url: Option<&str>) -> Option<String> {
+    if target.starts_with('/') {
+        return is_safe_path(target).then(|| target.to_owned());
+    }
+
+    let url = Url::parse(target).ok()?;
+    let base_url = Url::parse(base_url?).ok()?;
+
+    if url.origin() != base_url.origin() {
+        return None;
+    }
+
+    let path = match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_owned(),
+    };
+
+    is_safe_path(&path).then_some(path)
+}
+
+/// Returns the location to redirect to, or `/` if the target is not set or is not on this site.
+pub fn redirect_target_or_root(target: Option<&str>) -> String {
+    target
+        .and_then(safe_redirect_target)
+        .unwrap_or_else(|| "/".to_owned())
+}
+
+/// Returns a random token with 256 bits of entropy, hex encoded.
+pub fn random_hex_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    hex(&bytes)
+}
+
+/// Returns the bytes as a lowercase hex string.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn is_safe_path(path: &str) -> bool {
+    path.starts_with('/')
+        && !path.starts_with("//")
+        && !path.contains('\\')
+        && !path.chars().any(|c| c.is_control())
+        && path.len() <= 2048
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE_URL: &str = "https://example.com/app/";
+
+    fn target(target: &str) -> Option<String> {
+        redirect_target_on(target, Some(BASE_URL))
+    }
+
+    #[test]
+    fn paths_on_this_site_are_kept() {
+        assert_eq!(target("/"), Some("/".to_owned()));
+        assert_eq!(target("/account?tab=1"), Some("/account?tab=1".to_owned()));
+    }
+
+    #[test]
+    fn paths_to_other_origins_are_rejected() {
+        assert_eq!(target("//evil.com"), None);
+        assert_eq!(target("//evil.com/account"), None);
+        assert_eq!(target("/\\evil.com"), None);
+        assert_eq!(target("/\\/evil.com"), None);
+        assert_eq!(target("/account\\..\\..\\evil.com"), None);
+        assert_eq!(target("evil.com"), None);
+        assert_eq!(target(""), None);
+    }
+
+    #[test]
+    fn paths_with_control_characters_are_rejected() {
+        assert_eq!(target("/\t/evil.com"), None);
+        assert_eq!(target("/\n/evil.com"), None);
+        assert_eq!(target("/account\r\nLocation: https://evil.com"), None);
+        assert_eq!(target("/account\0"), None);
+    }
+
+    #[test]
+    fn too_long_paths_are_rejected() {
+        assert_eq!(target(&format!("/{}", "a".repeat(2048))), None);
+    }
+
+    #[test]
+    fn urls_on_the_base_url_origin_become_paths() {
+        // The path of `BASE_URL` is not a restriction, the whole origin is this site
+        assert_eq!(
+            target("https://example.com/app/account?tab=1#top"),
+            Some("/app/account?tab=1".to_owned())
+        );
+        assert_eq!(target("https://example.com"), Some("/".to_owned()));
+        assert_eq!(
+            target("https://example.com/other"),
+            Some("/other".to_owned())
+        );
+    }
+
+    #[test]
+    fn urls_on_other_origins_are_rejected() {
+        assert_eq!(target("https://evil.com/app/"), None);
+        assert_eq!(target("http://example.com/app/"), None);
+        assert_eq!(target("https://example.com:8443/app/"), None);
+        assert_eq!(target("https://example.com.evil.com/app/"), None);
+        assert_eq!(target("https://example.com@evil.com/app/"), None);
+        assert_eq!(target("javascript:alert(1)"), None);
+        assert_eq!(target("data:text/html,<script>alert(1)</script>"), None);
+    }
+
+    #[test]
+    fn urls_turned_into_paths_to_other_origins_are_rejected() {
+        // The url parser strips the tabs and newlines, leaving a protocol relative path
+        assert_eq!(target("https://example.com//evil.com"), None);
+        assert_eq!(target("https://example.com/\t/evil.com"), None);
+        assert_eq!(target("https://example.com/\\evil.com"), None);
+    }
+
+    #[test]
+    fn bytes_are_hex_encoded() {
+        assert_eq!(hex(&[]), "");
+        assert_eq!(hex(&[0x00, 0x0f, 0xa5, 0xff]), "000fa5ff");
+
+        let token = random_hex_token();
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, random_hex_token());
+    }
+
+    #[test]
+    fn urls_are_rejected_without_base_url() {
+        assert_eq!(redirect_target_on("https://example.com/", None), None);
+        assert_eq!(
+            redirect_target_on("/account", None),
+            Some("/account".to_owned())
+        );
+    }
+}
@@ -1,33 +1,77 @@
 use chrono::NaiveDateTime;
 use uuid::Uuid;
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct User {
     pub id: Uuid,
-    pub account_id: String,
-    pub provider: AuthProvider,
     pub username: String,
     pub image_url: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub last_login_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct UserIdentity {
+    pub user_id: Uuid,
+    pub provider: AuthProvider,
+    pub account_id: String,
+    pub created_at: NaiveDateTime,
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
 pub struct UserSession {
     pub id: Uuid,
     pub user_id: Uuid,
+    pub remember_me: bool,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
     pub created_at: NaiveDateTime,
     pub expires_at: NaiveDateTime,
+    pub last_seen_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct OAuthFlow {
+    pub state: String,
+    pub provider: AuthProvider,
+    #[serde(skip_serializing)]
+    pub pkce_code_verifier: String,
+    #[serde(skip_serializing)]
+    pub nonce: String,
+    pub return_to: Option<String>,
+    pub remember_me: bool,
+    pub link: bool,
+    #[serde(skip_serializing)]
+    pub browser_id: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct UserToken {
+    pub user_id: Uuid,
+    pub provider: AuthProvider,
+    #[serde(skip_serializing)]
+    pub access_token: String,
+    #[serde(skip_serializing)]
+    pub refresh_token: Option<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub updated_at: NaiveDateTime,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize)]
 struct UnknownProvider {
     _priv: (),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize)]
 pub enum AuthProvider {
     Google,
     Github,
     Discord,
+    Oidc,
+    Mock,
 
     // This variant should not be constructed
     #[allow(private_interfaces)]
@@ -40,6 +84,8 @@ impl From<String> for AuthProvider {
             "google" => AuthProvider::Google,
             "github" => AuthProvider::Github,
             "discord" => AuthProvider::Discord,
+            "oidc" => AuthProvider::Oidc,
+            "mock" => AuthProvider::Mock,
             _ => AuthProvider::Unknown(UnknownProvider { _priv: () }),
         }
     }
@@ -51,6 +97,8 @@ impl std::fmt::Display for AuthProvider {
             AuthProvider::Google => write!(f, "google"),
             AuthProvider::Github => write!(f, "github"),
             AuthProvider::Discord => write!(f, "discord"),
+            AuthProvider::Oidc => write!(f, "oidc"),
+            AuthProvider::Mock => write!(f, "mock"),
             _ => write!(f, "unknown provider"),
         }
     }
@@ -1,26 +1,12 @@
 use anyhow::Context;
-use axum::{
-    Extension, Router,
-    extract::Query,
-    http::StatusCode,
-    response::{IntoResponse, Redirect},
-    routing::get,
-};
+use oauth2::{AccessToken, Scope};
 
-use oauth2::PkceCodeVerifier;
-use oauth2::{AuthUrl, ClientId, ClientSecret, RedirectUrl, TokenUrl};
-use oauth2::{AuthorizationCode, CsrfToken, PkceCodeChallenge, TokenResponse, basic::BasicClient};
-
-use crate::{
-    constants::{
-        COOKIE_AUTH_CODE_VERIFIER, COOKIE_AUTH_CSRF_STATE, COOKIE_AUTH_SESSION, SESSION_DURATION,
-    },
-    misc::error::AppError,
-    models::AuthProvider,
-    routes::api::auth::ClientSettings,
+use super::{
+    ClientSettings,
+    config::ProviderConfig,
+    provider::{OAuthProvider, ProviderProfile},
 };
-use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
-use sqlx::SqlitePool;
+use crate::models::{AuthProvider, UserToken};
 
 // Checkout available fields on: https://docs.github.com/en/rest/users/users?apiVersion=2022-11-28#get-the-authenticated-user
 #[derive(Default, serde::Serialize, serde::Deserialize)]
@@ -31,192 +17,240 @@ struct GithubUser {
     avatar_url: String,
 }
 
-pub fn github_auth_router() -> Router {
-    Router::new()
-        .route("/api/auth/github/login", get(login))
-        .route("/api/auth/github/callback", get(callback))
+// Checkout available fields on: https://docs.github.com/en/rest/users/emails?apiVersion=2022-11-28#list-email-addresses-for-the-authenticated-user
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct GithubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
 }
 
-fn get_client_settings() -> Result<ClientSettings, AppError> {
-    let client_id = ClientId::new(
-        std::env::var("GITHUB_CLIENT_ID")
-            .context("Missing the GITHUB_CLIENT_ID environment variable")?,
-    );
-
-    let client_secret = ClientSecret::new(
-        std::env::var("GITHUB_CLIENT_SECRET")
-            .context("Missing the GITHUB_CLIENT_SECRET environment variable")?,
-    );
-
-    let auth_url = AuthUrl::new("https://github.com/login/oauth/authorize".to_string())
-        .context("Invalid authorization endpoint URL")?;
-    let token_url = TokenUrl::new("https://github.com/login/oauth/access_token".to_string())
-        .context("Invalid token endpoint URL")?;
-
-    let base_url = std::env::var("BASE_URL").context("Failed to get app base url")?;
-    let redirect_url = RedirectUrl::new(format!("{base_url}/api/auth/github/callback"))
-        .context("Invalid redirect url")?;
-
-    Ok(ClientSettings {
-        client_id,
-        client_secret,
-        auth_url,
-        token_url,
-        redirect_url,
-    })
+#[derive(serde::Serialize)]
+struct GithubGrantRequest<'a> {
+    access_token: &'a str,
 }
 
-async fn login() -> Result<impl IntoResponse, AppError> {
-    let client_settings = get_client_settings()?;
-    let client = BasicClient::new(client_settings.client_id)
-        .set_client_secret(client_settings.client_secret)
-        .set_auth_uri(client_settings.auth_url)
-        .set_token_uri(client_settings.token_url)
-        // Set the URL the user will be redirected to after the authorization process.
-        .set_redirect_uri(client_settings.redirect_url);
-
-    let (pkce_code_challenge, pkce_code_verifier) = PkceCodeChallenge::new_random_sha256();
-
-    let (authorize_url, csrf_state) = client
-        .authorize_url(CsrfToken::new_random)
-        .set_pkce_challenge(pkce_code_challenge)
-        .url();
-
-    // Set csrf and code verifier cookies, these are short lived cookies
-    let cookie_max_age = cookie::time::Duration::minutes(5);
-    let csrf_cookie: Cookie =
-        Cookie::build((COOKIE_AUTH_CSRF_STATE, csrf_state.secret().to_owned()))
-            .http_only(true)
-            .path("/")
-            .same_site(SameSite::Lax)
-            .max_age(cookie_max_age)
-            .into();
-
-    let code_verifier: Cookie = Cookie::build((
-        COOKIE_AUTH_CODE_VERIFIER,
-        pkce_code_verifier.secret().to_owned(),
-    ))
-    .http_only(true)
-    .path("/")
-    .same_site(SameSite::Lax)
-    .max_age(cookie_max_age)
-    .into();
-
-    let cookies = CookieJar::new().add(csrf_cookie).add(code_verifier);
-
-    Ok((cookies, Redirect::to(authorize_url.as_str())))
+pub struct GithubProvider {
+    config: ProviderConfig,
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct AuthRequest {
-    code: String,
-    state: String,
+impl GithubProvider {
+    pub fn new(config: ProviderConfig) -> Self {
+        GithubProvider { config }
+    }
+
+    /// The REST API base url, this is different on Github Enterprise.
+    fn api_url(&self) -> &str {
+        self.config
+            .api_url
+            .as_deref()
+            .unwrap_or("https://api.github.com")
+            .trim_end_matches('/')
+    }
 }
 
-async fn callback(
-    cookies: CookieJar,
-    Extension(pool): Extension<SqlitePool>,
-    Query(query): Query<AuthRequest>,
-) -> Result<impl IntoResponse, AppError> {
-    let code = query.code;
-    let state = query.state;
-    let stored_state = cookies.get(COOKIE_AUTH_CSRF_STATE);
-    let stored_code_verifier = cookies.get(COOKIE_AUTH_CODE_VERIFIER);
-
-    let (Some(csrf_state), Some(code_verifier)) = (stored_state, stored_code_verifier) else {
-        return Ok(StatusCode::BAD_REQUEST.into_response());
-    };
-
-    if csrf_state.value() != state {
-        return Ok(StatusCode::BAD_REQUEST.into_response());
+#[async_trait::async_trait]
+impl OAuthProvider for GithubProvider {
+    fn kind(&self) -> AuthProvider {
+        AuthProvider::Github
+    }
+
+    fn display_name(&self) -> String {
+        "Github".to_owned()
+    }
+
+    fn logo_url(&self) -> Option<&str> {
+        Some("/public/images/github-logo.png")
+    }
+
+    fn config(&self) -> &ProviderConfig {
+        &self.config
+    }
+
+    async fn client_settings(&self) -> Result<ClientSettings, anyhow::Error> {
+        let config = &self.config;
+
+        ClientSettings::from_env(
+            AuthProvider::Github,
+            config
+                .auth_url
+                .as_deref()
+                .unwrap_or("https://github.com/login/oauth/authorize"),
+            config
+                .token_url
+                .as_deref()
+                .unwrap_or("https://github.com/login/oauth/access_token"),
+        )
+    }
+
+    fn scopes(&self) -> Vec<Scope> {
+        // Required to read the email when the user keeps it private
+        vec![Scope::new("user:email".to_string())]
+    }
+
+    async fn fetch_profile(
+        &self,
+        http_client: &reqwest::Client,
+        access_token: &AccessToken,
+    ) -> Result<ProviderProfile, anyhow::Error> {
+        let github_user = http_client
+            .get(format!("{}/user", self.api_url()))
+            .header("User-Agent", "Rust") // An user agent is required for github
+            .bearer_auth(access_token.secret())
+            .send()
+            .await
+            .context("Failed to get user info")?
+            .error_for_status()
+            .context("Failed to get user info")?
+            .json::<GithubUser>()
+            .await
+            .context("Failed to convert user info to Json")?;
+
+        // Github only allows verified emails as the public email, if the email is private
+        // we use the primary email if verified
+        let (email, email_verified) = match github_user.email {
+            Some(email) => (Some(email), true),
+            None => match fetch_primary_email(http_client, self.api_url(), access_token).await? {
+                Some(email) => (Some(email), true),
+                None => (None, false),
+            },
+        };
+
+        let username = github_user
+            .name
+            .or_else(|| email.clone())
+            .unwrap_or_else(|| "<unknown>".to_owned());
+
+        Ok(ProviderProfile {
+            account_id: github_user.id.to_string(),
+            username,
+            image_url: Some(github_user.avatar_url),
+            email,
+            email_verified,
+        })
+    }
+
+    async fn revoke_token(
+        &self,
+        http_client: &reqwest::Client,
+        user_token: &UserToken,
+    ) -> Result<(), anyhow::Error> {
+        // Github doesn't support RFC 7009, deleting the grant revokes all the tokens of the user
+        // See: https://docs.github.com/en/rest/apps/oauth-applications?apiVersion=2022-11-28#delete-an-app-authorization
+        let client_settings = self.client_settings().await?;
+        let client_id = client_settings.client_id.as_str();
+
+        http_client
+            .delete(format!(
+                "{api_url}/applications/{client_id}/grant",
+                api_url = self.api_url()
+            ))
+            .header("User-Agent", "Rust") // An user agent is required for github
+            .header("Accept", "application/vnd.github+json")
+            .basic_auth(client_id, Some(client_settings.client_secret.secret()))
+            .json(&GithubGrantRequest {
+                access_token: &user_token.access_token,
+            })
+            .send()
+            .await
+            .context("Failed to revoke token")?
+            .error_for_status()
+            .context("Failed to revoke token")?;
+
+        Ok(())
     }
+}
 
-    let client_settings = get_client_settings()?;
-    let client = BasicClient::new(client_settings.client_id)
-        .set_client_secret(client_settings.client_secret)
-        .set_auth_uri(client_settings.auth_url)
-        .set_token_uri(client_settings.token_url)
-        // Set the URL the user will be redirected to after the authorization process.
-        .set_redirect_uri(client_settings.redirect_url);
-
-    let http_client = reqwest::ClientBuilder::new()
-        // Following redirects opens the client up to SSRF vulnerabilities.
-        .redirect(reqwest::redirect::Policy::none())
-        .build()?;
-
-    let code = AuthorizationCode::new(code);
-    let pkce_code_verifier = PkceCodeVerifier::new(code_verifier.value().to_owned());
-
-    let token_response = client
-        .exchange_code(code)
-        // Set the PKCE code verifier.
-        .set_pkce_verifier(pkce_code_verifier)
-        .request_async(&http_client)
-        .await?;
-
-    // Get the Github user info
-    let github_user = reqwest::Client::new()
-        .get("https://api.github.com/user")
+async fn fetch_primary_email(
+    http_client: &reqwest::Client,
+    api_url: &str,
+    access_token: &AccessToken,
+) -> Result<Option<String>, anyhow::Error> {
+    let emails = http_client
+        .get(format!("{api_url}/user/emails"))
         .header("User-Agent", "Rust") // An user agent is required for github
-        .bearer_auth(token_response.access_token().secret())
+        .bearer_auth(access_token.secret())
         .send()
         .await
-        .context("Failed to get user info")?
-        .json::<GithubUser>()
+        .context("Failed to get user emails")?
+        .error_for_status()
+        .context("Failed to get user emails")?
+        .json::<Vec<GithubEmail>>()
         .await
-        .context("Failed to convert user info to Json")?;
-
-    // Add user session
-    let account_id = github_user.id.to_string();
-    let username = github_user
-        .name
-        .or(github_user.email)
-        .unwrap_or_else(|| "<unknown>".to_owned());
-    let existing_user =
-        crate::db::get_user_by_account_id(&pool, AuthProvider::Github, account_id.clone())
-            .await
-            .context("Failed to get user")?;
+        .context("Failed to convert user emails to Json")?;
 
-    let user = match existing_user {
-        Some(x) => x,
-        None => crate::db::create_user(
-            &pool,
-            account_id,
-            AuthProvider::Github,
-            username,
-            Some(github_user.avatar_url),
-        )
-        .await
-        .context("Failed to create user")?,
-    };
+    let primary_email = emails
+        .into_iter()
+        .find(|x| x.primary && x.verified)
+        .map(|x| x.email);
 
-    let user_session = crate::db::create_user_session(&pool, user.id, SESSION_DURATION)
-        .await
-        .context("Failed to create user session")?;
-
-    // Remove code_verifier and csrf_state cookies
-    let mut remove_csrf_cookie = Cookie::new(COOKIE_AUTH_CSRF_STATE, "");
-    remove_csrf_cookie.set_path("/");
-    remove_csrf_cookie.make_removal();
-
-    let mut remove_code_verifier = Cookie::new(COOKIE_AUTH_CODE_VERIFIER, "");
-    remove_code_verifier.set_path("/");
-    remove_code_verifier.make_removal();
-
-    let session_cookie: Cookie = Cookie::build((COOKIE_AUTH_SESSION, user_session.id.to_string()))
-        .same_site(SameSite::Lax)
-        .http_only(true)
-        .path("/")
-        .max_age(cookie::time::Duration::milliseconds(
-            SESSION_DURATION.as_millis() as i64,
-        ))
-        .into();
-
-    let cookies = CookieJar::new()
-        .add(remove_csrf_cookie)
-        .add(remove_code_verifier)
-        .add(session_cookie);
-
-    let response = (cookies, Redirect::to("/")).into_response();
-    Ok(response)
+    Ok(primary_email)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Json, Router, http::StatusCode, routing::get};
+
+    /// Serves the user and emails endpoints of the Github API on a random local port.
+    async fn spawn_api(user_status: StatusCode, emails_status: StatusCode) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_url = format!("http://{}", listener.local_addr().unwrap());
+
+        let router = Router::new()
+            .route(
+                "/user",
+                get(move || async move {
+                    let user = GithubUser {
+                        id: 1,
+                        avatar_url: "https://avatars.githubusercontent.com/u/1".to_owned(),
+                        ..Default::default()
+                    };
+                    (user_status, Json(user))
+                }),
+            )
+            .route(
+                "/user/emails",
+                get(move || async move { (emails_status, Json(Vec::<GithubEmail>::new())) }),
+            );
+
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        api_url
+    }
+
+    fn provider(api_url: String) -> GithubProvider {
+        GithubProvider::new(ProviderConfig {
+            api_url: Some(api_url),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn error_responses_are_reported_with_their_status() {
+        let access_token = AccessToken::new("token".to_owned());
+
+        let api_url = spawn_api(StatusCode::UNAUTHORIZED, StatusCode::OK).await;
+        let err = provider(api_url)
+            .fetch_profile(&reqwest::Client::new(), &access_token)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<reqwest::Error>()
+                .and_then(|x| x.status()),
+            Some(reqwest::StatusCode::UNAUTHORIZED),
+            "{err:#}"
+        );
+
+        let api_url = spawn_api(StatusCode::OK, StatusCode::FORBIDDEN).await;
+        let err = provider(api_url)
+            .fetch_profile(&reqwest::Client::new(), &access_token)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<reqwest::Error>()
+                .and_then(|x| x.status()),
+            Some(reqwest::StatusCode::FORBIDDEN),
+            "{err:#}"
+        );
+    }
 }
@@ -1,223 +1,184 @@
 use anyhow::Context;
-use axum::{
-    Extension, Router,
-    extract::Query,
-    http::StatusCode,
-    response::{IntoResponse, Redirect},
-    routing::get,
-};
+use oauth2::{AccessToken, Scope};
 
-use oauth2::PkceCodeVerifier;
-use oauth2::{AuthUrl, ClientId, ClientSecret, RedirectUrl, TokenUrl};
-use oauth2::{
-    AuthorizationCode, CsrfToken, PkceCodeChallenge, Scope, TokenResponse, basic::BasicClient,
+use super::{
+    ClientSettings,
+    config::ProviderConfig,
+    id_token::{IdTokenClaims, JwksCache},
+    provider::{OAuthProvider, ProviderProfile, ProviderTokenResponse},
 };
+use crate::models::AuthProvider;
 
-use crate::{
-    constants::{
-        COOKIE_AUTH_CODE_VERIFIER, COOKIE_AUTH_CSRF_STATE, COOKIE_AUTH_SESSION, SESSION_DURATION,
-    },
-    misc::error::AppError,
-    models::AuthProvider,
-    routes::api::auth::ClientSettings,
-};
-use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
-use sqlx::SqlitePool;
+// Google can use any of these as the id token issuer
+const GOOGLE_ISSUERS: &[&str] = &["https://accounts.google.com", "accounts.google.com"];
 
 //  Checkout available fields on: https://developers.google.com/identity/openid-connect/openid-connect
 #[derive(Default, serde::Serialize, serde::Deserialize)]
 struct GoogleUser {
     sub: String,
-    name: String,
+    name: Option<String>,
     email: Option<String>,
     email_verified: Option<bool>,
-    picture: String,
+    picture: Option<String>,
+    nonce: Option<String>,
 }
 
-pub fn google_auth_router() -> Router {
-    Router::new()
-        .route("/api/auth/google/login", get(login))
-        .route("/api/auth/google/callback", get(callback))
+impl IdTokenClaims for GoogleUser {
+    fn nonce(&self) -> Option<&str> {
+        self.nonce.as_deref()
+    }
 }
 
-fn get_client_settings() -> Result<ClientSettings, AppError> {
-    let client_id = ClientId::new(
-        std::env::var("GOOGLE_CLIENT_ID")
-            .context("Missing the GOOGLE_CLIENT_ID environment variable")?,
-    );
-
-    let client_secret = ClientSecret::new(
-        std::env::var("GOOGLE_CLIENT_SECRET")
-            .context("Missing the GOOGLE_CLIENT_SECRET environment variable")?,
-    );
-
-    let auth_url = AuthUrl::new("https://accounts.google.com/o/oauth2/v2/auth".to_string())
-        .context("Invalid authorization endpoint URL")?;
-    let token_url = TokenUrl::new("https://www.googleapis.com/oauth2/v3/token".to_string())
-        .context("Invalid token endpoint URL")?;
-
-    let base_url = std::env::var("BASE_URL").context("Failed to get app base url")?;
-    let redirect_url = RedirectUrl::new(format!("{base_url}/api/auth/google/callback"))
-        .context("Invalid redirect url")?;
-
-    Ok(ClientSettings {
-        client_id,
-        client_secret,
-        auth_url,
-        token_url,
-        redirect_url,
-    })
+pub struct GoogleProvider {
+    config: ProviderConfig,
+    jwks: JwksCache,
 }
 
-async fn login() -> Result<impl IntoResponse, AppError> {
-    let client_settings = get_client_settings()?;
-    let client = BasicClient::new(client_settings.client_id)
-        .set_client_secret(client_settings.client_secret)
-        .set_auth_uri(client_settings.auth_url)
-        .set_token_uri(client_settings.token_url)
-        // Set the URL the user will be redirected to after the authorization process.
-        .set_redirect_uri(client_settings.redirect_url);
-
-    let (pkce_code_challenge, pkce_code_verifier) = PkceCodeChallenge::new_random_sha256();
-
-    let (authorize_url, csrf_state) = client
-        .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new(
-            "https://www.googleapis.com/auth/userinfo.profile".to_string(),
-        ))
-        .set_pkce_challenge(pkce_code_challenge)
-        .url();
-
-    // Set csrf and code verifier cookies, these are short lived cookies
-    let cookie_max_age = cookie::time::Duration::minutes(5);
-    let csrf_cookie: Cookie =
-        Cookie::build((COOKIE_AUTH_CSRF_STATE, csrf_state.secret().to_owned()))
-            .http_only(true)
-            .path("/")
-            .same_site(SameSite::Lax)
-            .max_age(cookie_max_age)
-            .into();
-
-    let code_verifier: Cookie = Cookie::build((
-        COOKIE_AUTH_CODE_VERIFIER,
-        pkce_code_verifier.secret().to_owned(),
-    ))
-    .http_only(true)
-    .path("/")
-    .same_site(SameSite::Lax)
-    .max_age(cookie_max_age)
-    .into();
-
-    let cookies = CookieJar::new().add(csrf_cookie).add(code_verifier);
-
-    Ok((cookies, Redirect::to(authorize_url.as_str())))
+impl GoogleProvider {
+    pub fn new(config: ProviderConfig, http_client: reqwest::Client) -> Self {
+        GoogleProvider {
+            config,
+            jwks: JwksCache::new("https://www.googleapis.com/oauth2/v3/certs", http_client),
+        }
+    }
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct AuthRequest {
-    code: String,
-    state: String,
-}
+#[async_trait::async_trait]
+impl OAuthProvider for GoogleProvider {
+    fn kind(&self) -> AuthProvider {
+        AuthProvider::Google
+    }
 
-async fn callback(
-    cookies: CookieJar,
-    Extension(pool): Extension<SqlitePool>,
-    Query(query): Query<AuthRequest>,
-) -> Result<impl IntoResponse, AppError> {
-    let code = query.code;
-    let state = query.state;
-    let stored_state = cookies.get(COOKIE_AUTH_CSRF_STATE);
-    let stored_code_verifier = cookies.get(COOKIE_AUTH_CODE_VERIFIER);
-
-    let (Some(csrf_state), Some(code_verifier)) = (stored_state, stored_code_verifier) else {
-        return Ok(StatusCode::BAD_REQUEST.into_response());
-    };
-
-    if csrf_state.value() != state {
-        return Ok(StatusCode::BAD_REQUEST.into_response());
+    fn display_name(&self) -> String {
+        "Google".to_owned()
     }
 
-    let client_settings = get_client_settings()?;
-    let client = BasicClient::new(client_settings.client_id)
-        .set_client_secret(client_settings.client_secret)
-        .set_auth_uri(client_settings.auth_url)
-        .set_token_uri(client_settings.token_url)
-        // Set the URL the user will be redirected to after the authorization process.
-        .set_redirect_uri(client_settings.redirect_url);
-
-    let http_client = reqwest::ClientBuilder::new()
-        // Following redirects opens the client up to SSRF vulnerabilities.
-        .redirect(reqwest::redirect::Policy::none())
-        .build()?;
-
-    let code = AuthorizationCode::new(code);
-    let pkce_code_verifier = PkceCodeVerifier::new(code_verifier.value().to_owned());
-
-    let token_response = client
-        .exchange_code(code)
-        .set_pkce_verifier(pkce_code_verifier)
-        .request_async(&http_client)
-        .await
-        .context("Failed to get token response")?;
-
-    // Get the Google user info
-    let google_user = reqwest::Client::new()
-        .get("https://www.googleapis.com/oauth2/v3/userinfo")
-        .bearer_auth(token_response.access_token().secret())
-        .send()
-        .await
-        .context("Failed to get user info")?
-        .json::<GoogleUser>()
-        .await
-        .context("Failed to convert user info to Json")?;
-
-    // Add user session
-    let account_id = google_user.sub.clone();
-    let existing_user =
-        crate::db::get_user_by_account_id(&pool, AuthProvider::Google, account_id.clone())
-            .await
-            .context("Failed to get user")?;
+    fn logo_url(&self) -> Option<&str> {
+        Some("/public/images/google-logo.png")
+    }
 
-    let user = match existing_user {
-        Some(x) => x,
-        None => crate::db::create_user(
-            &pool,
-            account_id,
+    fn config(&self) -> &ProviderConfig {
+        &self.config
+    }
+
+    async fn client_settings(&self) -> Result<ClientSettings, anyhow::Error> {
+        let config = &self.config;
+
+        ClientSettings::from_env(
             AuthProvider::Google,
-            google_user.name,
-            Some(google_user.picture),
+            config
+                .auth_url
+                .as_deref()
+                .unwrap_or("https://accounts.google.com/o/oauth2/v2/auth"),
+            config
+                .token_url
+                .as_deref()
+                .unwrap_or("https://www.googleapis.com/oauth2/v3/token"),
+        )?
+        .with_revocation_url(
+            config
+                .revocation_url
+                .as_deref()
+                .unwrap_or("https://oauth2.googleapis.com/revoke"),
         )
-        .await
-        .context("Failed to create user")?,
-    };
-
-    let user_session = crate::db::create_user_session(&pool, user.id, SESSION_DURATION)
-        .await
-        .context("Failed to create user session")?;
-
-    // Remove code_verifier and csrf_state cookies
-    let mut remove_csrf_cookie = Cookie::new(COOKIE_AUTH_CSRF_STATE, "");
-    remove_csrf_cookie.set_path("/");
-    remove_csrf_cookie.make_removal();
-
-    let mut remove_code_verifier = Cookie::new(COOKIE_AUTH_CODE_VERIFIER, "");
-    remove_code_verifier.set_path("/");
-    remove_code_verifier.make_removal();
-
-    let session_cookie: Cookie = Cookie::build((COOKIE_AUTH_SESSION, user_session.id.to_string()))
-        .same_site(SameSite::Lax)
-        .http_only(true)
-        .path("/")
-        .max_age(cookie::time::Duration::milliseconds(
-            SESSION_DURATION.as_millis() as i64,
-        ))
-        .into();
-
-    let cookies = CookieJar::new()
-        .add(remove_csrf_cookie)
-        .add(remove_code_verifier)
-        .add(session_cookie);
-
-    let response = (cookies, Redirect::to("/")).into_response();
-    Ok(response)
+    }
+
+    fn scopes(&self) -> Vec<Scope> {
+        ["openid", "profile", "email"]
+            .into_iter()
+            .map(|scope| Scope::new(scope.to_owned()))
+            .collect()
+    }
+
+    fn auth_params(&self) -> Vec<(&str, &str)> {
+        // Google only returns a refresh token for offline access
+        vec![("access_type", "offline")]
+    }
+
+    async fn fetch_profile(
+        &self,
+        http_client: &reqwest::Client,
+        access_token: &AccessToken,
+    ) -> Result<ProviderProfile, anyhow::Error> {
+        let google_user = http_client
+            .get(
+                self.config
+                    .userinfo_url
+                    .as_deref()
+                    .unwrap_or("https://www.googleapis.com/oauth2/v3/userinfo"),
+            )
+            .bearer_auth(access_token.secret())
+            .send()
+            .await
+            .context("Failed to get user info")?
+            .error_for_status()
+            .context("Failed to get user info")?
+            .json::<GoogleUser>()
+            .await
+            .context("Failed to convert user info to Json")?;
+
+        Ok(google_user.into())
+    }
+
+    async fn profile_from_token(
+        &self,
+        _http_client: &reqwest::Client,
+        token_response: &ProviderTokenResponse,
+        nonce: &str,
+    ) -> Result<ProviderProfile, anyhow::Error> {
+        // The id token already contains the user info, so we don't need to request it
+        let id_token = token_response
+            .extra_fields()
+            .id_token
+            .as_deref()
+            .context("Google did not return an id token")?;
+
+        let client_settings = self.client_settings().await?;
+        let google_user = self
+            .jwks
+            .verify::<GoogleUser>(
+                id_token,
+                GOOGLE_ISSUERS,
+                client_settings.client_id.as_str(),
+                nonce,
+            )
+            .await?;
+
+        Ok(google_user.into())
+    }
+}
+
+impl From<GoogleUser> for ProviderProfile {
+    fn from(google_user: GoogleUser) -> Self {
+        // The id token only has the profile claims if the user shared them
+        let username = google_user
+            .name
+            .or_else(|| google_user.email.clone())
+            .unwrap_or_else(|| "<unknown>".to_owned());
+
+        ProviderProfile {
+            account_id: google_user.sub,
+            username,
+            image_url: google_user.picture,
+            email: google_user.email,
+            email_verified: google_user.email_verified.unwrap_or(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_claims_are_optional() {
+        let profile = ProviderProfile::from(GoogleUser {
+            sub: "1".to_owned(),
+            email: Some("test@example.com".to_owned()),
+            ..Default::default()
+        });
+
+        assert_eq!(profile.username, "test@example.com");
+        assert_eq!(profile.image_url, None);
+    }
 }
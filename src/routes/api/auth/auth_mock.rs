@@ -0,0 +1,471 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use askama::Template;
+use axum::{
+    Extension, Form, Json, Router,
+    extract::Query,
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect},
+    routing::{get, post},
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{Authorization, authorization::Basic},
+};
+use oauth2::{
+    AccessToken, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge,
+    PkceCodeVerifier, RefreshToken, basic::BasicTokenType, url::Url,
+};
+
+use super::{
+    ClientSettings,
+    config::ProviderConfig,
+    provider::{IdTokenFields, OAuthProvider, ProviderProfile, ProviderTokenResponse},
+};
+use crate::{
+    misc::error::AppError,
+    models::{AuthProvider, UserToken},
+};
+
+const MOCK_CLIENT_ID: &str = "mock-client";
+const MOCK_CLIENT_SECRET: &str = "mock-secret";
+
+/// Time the authorization code can be exchanged for a token.
+const MOCK_CODE_DURATION: Duration = Duration::from_secs(60);
+
+/// Time the mock access tokens are valid.
+const MOCK_TOKEN_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// A fake provider that serves its own authorization page and token endpoint, this allows to
+/// login without network access or provider credentials.
+///
+/// The callback exchanges the codes and resolves the identities in process instead of requesting
+/// the token endpoint, so the login works even if `BASE_URL` is not reachable from the server.
+///
+/// The authorization page lets the user pick any identity, so this must never be enabled
+/// in production.
+pub struct MockProvider {
+    config: ProviderConfig,
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    codes: HashMap<String, MockGrant>,
+    access_tokens: HashMap<String, MockAccess>,
+    refresh_tokens: HashMap<String, ProviderProfile>,
+}
+
+struct MockGrant {
+    profile: ProviderProfile,
+    code_challenge: String,
+    redirect_uri: String,
+    expires_at: Instant,
+}
+
+struct MockAccess {
+    profile: ProviderProfile,
+    expires_at: Instant,
+}
+
+impl MockProvider {
+    /// Creates the provider if the `MOCK_AUTH_ENABLED` environment variable is `true`.
+    pub fn from_env(config: ProviderConfig) -> Option<Self> {
+        let enabled = std::env::var("MOCK_AUTH_ENABLED").is_ok_and(|x| x == "true");
+        if !enabled {
+            return None;
+        }
+
+        tracing::warn!("The mock auth provider is enabled, anyone can login as any user");
+
+        Some(MockProvider::new(config))
+    }
+
+    pub fn new(config: ProviderConfig) -> Self {
+        MockProvider {
+            config,
+            state: Arc::new(Mutex::new(MockState::default())),
+        }
+    }
+}
+
+impl MockState {
+    /// Exchanges an authorization code for the tokens of its identity, each code can only be
+    /// used once.
+    fn exchange_code(
+        &mut self,
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+    ) -> Option<MockTokenResponse> {
+        let grant = self.codes.remove(code)?;
+
+        let code_challenge = PkceCodeChallenge::from_code_verifier_sha256(&PkceCodeVerifier::new(
+            code_verifier.to_owned(),
+        ));
+
+        if grant.expires_at <= Instant::now()
+            || code_challenge.as_str() != grant.code_challenge
+            || redirect_uri != grant.redirect_uri
+        {
+            return None;
+        }
+
+        let refresh_token = CsrfToken::new_random().secret().to_owned();
+        self.refresh_tokens
+            .insert(refresh_token.clone(), grant.profile.clone());
+
+        Some(self.issue_access_token(grant.profile, Some(refresh_token)))
+    }
+
+    fn exchange_refresh_token(&mut self, refresh_token: &str) -> Option<MockTokenResponse> {
+        let profile = self.refresh_tokens.get(refresh_token).cloned()?;
+        Some(self.issue_access_token(profile, None))
+    }
+
+    fn issue_access_token(
+        &mut self,
+        profile: ProviderProfile,
+        refresh_token: Option<String>,
+    ) -> MockTokenResponse {
+        let now = Instant::now();
+        let access_token = CsrfToken::new_random().secret().to_owned();
+        self.access_tokens
+            .retain(|_, access| access.expires_at > now);
+        self.access_tokens.insert(
+            access_token.clone(),
+            MockAccess {
+                profile,
+                expires_at: now + MOCK_TOKEN_DURATION,
+            },
+        );
+
+        MockTokenResponse {
+            access_token,
+            token_type: "bearer",
+            expires_in: MOCK_TOKEN_DURATION.as_secs(),
+            refresh_token,
+        }
+    }
+
+    /// Returns the identity of the access token, if it has not expired.
+    fn profile(&self, access_token: &str) -> Option<ProviderProfile> {
+        self.access_tokens
+            .get(access_token)
+            .filter(|access| access.expires_at > Instant::now())
+            .map(|access| access.profile.clone())
+    }
+}
+
+#[async_trait::async_trait]
+impl OAuthProvider for MockProvider {
+    fn kind(&self) -> AuthProvider {
+        AuthProvider::Mock
+    }
+
+    fn display_name(&self) -> String {
+        "Mock".to_owned()
+    }
+
+    fn config(&self) -> &ProviderConfig {
+        &self.config
+    }
+
+    async fn client_settings(&self) -> Result<ClientSettings, anyhow::Error> {
+        let base_url = std::env::var("BASE_URL").context("Failed to get app base url")?;
+
+        ClientSettings::new(
+            AuthProvider::Mock,
+            ClientId::new(MOCK_CLIENT_ID.to_owned()),
+            ClientSecret::new(MOCK_CLIENT_SECRET.to_owned()),
+            &format!("{base_url}/api/auth/mock/authorize"),
+            &format!("{base_url}/api/auth/mock/token"),
+        )
+    }
+
+    fn router(&self) -> Router {
+        Router::new()
+            .route(
+                "/api/auth/mock/authorize",
+                get(authorize_page).post(authorize),
+            )
+            .route("/api/auth/mock/token", post(token))
+            .layer(Extension(self.state.clone()))
+    }
+
+    async fn exchange_code(
+        &self,
+        _http_client: &reqwest::Client,
+        code: AuthorizationCode,
+        pkce_code_verifier: PkceCodeVerifier,
+    ) -> Result<ProviderTokenResponse, anyhow::Error> {
+        let redirect_url = self.client_settings().await?.redirect_url;
+
+        let token_response = self
+            .state
+            .lock()
+            .unwrap()
+            .exchange_code(
+                code.secret(),
+                pkce_code_verifier.secret(),
+                redirect_url.as_str(),
+            )
+            .context("Invalid mock authorization code")?;
+
+        Ok(token_response.into())
+    }
+
+    async fn exchange_refresh_token(
+        &self,
+        _http_client: &reqwest::Client,
+        refresh_token: &RefreshToken,
+    ) -> Result<ProviderTokenResponse, anyhow::Error> {
+        let token_response = self
+            .state
+            .lock()
+            .unwrap()
+            .exchange_refresh_token(refresh_token.secret())
+            .context("Invalid mock refresh token")?;
+
+        Ok(token_response.into())
+    }
+
+    async fn fetch_profile(
+        &self,
+        _http_client: &reqwest::Client,
+        access_token: &AccessToken,
+    ) -> Result<ProviderProfile, anyhow::Error> {
+        self.state
+            .lock()
+            .unwrap()
+            .profile(access_token.secret())
+            .context("Invalid mock access token")
+    }
+
+    async fn revoke_token(
+        &self,
+        _http_client: &reqwest::Client,
+        user_token: &UserToken,
+    ) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.access_tokens.remove(&user_token.access_token);
+
+        if let Some(refresh_token) = &user_token.refresh_token {
+            state.refresh_tokens.remove(refresh_token);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct AuthorizeQuery {
+    client_id: String,
+    redirect_uri: String,
+    state: String,
+    code_challenge: String,
+    code_challenge_method: String,
+}
+
+struct MockIdentity {
+    account_id: &'static str,
+    username: &'static str,
+    email: &'static str,
+    email_verified: bool,
+}
+
+const MOCK_IDENTITIES: &[MockIdentity] = &[
+    MockIdentity {
+        account_id: "mock-alice",
+        username: "Alice",
+        email: "alice@example.com",
+        email_verified: true,
+    },
+    MockIdentity {
+        account_id: "mock-bob",
+        username: "Bob",
+        email: "bob@example.com",
+        email_verified: false,
+    },
+];
+
+#[derive(Template)]
+#[template(path = "mock_authorize.html")]
+struct MockAuthorizeTemplate {
+    query: AuthorizeQuery,
+    identities: &'static [MockIdentity],
+}
+
+impl IntoResponse for MockAuthorizeTemplate {
+    fn into_response(self) -> axum::response::Response {
+        match self.render() {
+            Ok(html) => Html(html).into_response(),
+            Err(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to render template: {err}"),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// Checks the client and redirect uri of an authorization request, we must not redirect to
+/// other urls than the provider callback.
+async fn is_valid_client(
+    provider: &dyn OAuthProvider,
+    client_id: &str,
+    redirect_uri: &str,
+) -> Result<bool, anyhow::Error> {
+    let client_settings = provider.client_settings().await?;
+
+    Ok(client_settings.client_id.as_str() == client_id
+        && client_settings.redirect_url.as_str() == redirect_uri)
+}
+
+/// Shows the form to pick the identity to login with.
+async fn authorize_page(
+    Extension(provider): Extension<Arc<dyn OAuthProvider>>,
+    Query(query): Query<AuthorizeQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    if !is_valid_client(provider.as_ref(), &query.client_id, &query.redirect_uri).await?
+        || query.code_challenge_method != "S256"
+    {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    let template = MockAuthorizeTemplate {
+        query,
+        identities: MOCK_IDENTITIES,
+    };
+
+    Ok(template.into_response())
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct AuthorizeForm {
+    client_id: String,
+    redirect_uri: String,
+    state: String,
+    code_challenge: String,
+    account_id: String,
+    username: String,
+    email: Option<String>,
+    email_verified: Option<String>,
+}
+
+/// Creates an authorization code for the selected identity and redirects to the callback.
+async fn authorize(
+    Extension(provider): Extension<Arc<dyn OAuthProvider>>,
+    Extension(state): Extension<Arc<Mutex<MockState>>>,
+    Form(form): Form<AuthorizeForm>,
+) -> Result<impl IntoResponse, AppError> {
+    if !is_valid_client(provider.as_ref(), &form.client_id, &form.redirect_uri).await?
+        || form.account_id.trim().is_empty()
+        || form.username.trim().is_empty()
+    {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    let profile = ProviderProfile {
+        account_id: form.account_id.trim().to_owned(),
+        username: form.username.trim().to_owned(),
+        image_url: None,
+        email: form
+            .email
+            .map(|x| x.trim().to_owned())
+            .filter(|x| !x.is_empty()),
+        email_verified: form.email_verified.is_some(),
+    };
+
+    let code = CsrfToken::new_random().secret().to_owned();
+    let now = Instant::now();
+
+    {
+        let mut state = state.lock().unwrap();
+        state.codes.retain(|_, grant| grant.expires_at > now);
+        state.codes.insert(
+            code.clone(),
+            MockGrant {
+                profile,
+                code_challenge: form.code_challenge,
+                redirect_uri: form.redirect_uri.clone(),
+                expires_at: now + MOCK_CODE_DURATION,
+            },
+        );
+    }
+
+    let mut redirect_url = Url::parse(&form.redirect_uri).context("Invalid redirect uri")?;
+    redirect_url
+        .query_pairs_mut()
+        .append_pair("code", &code)
+        .append_pair("state", &form.state);
+
+    Ok(Redirect::to(redirect_url.as_str()).into_response())
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct TokenRequest {
+    grant_type: String,
+    code: Option<String>,
+    code_verifier: Option<String>,
+    redirect_uri: Option<String>,
+    refresh_token: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct MockTokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: u64,
+    refresh_token: Option<String>,
+}
+
+impl From<MockTokenResponse> for ProviderTokenResponse {
+    fn from(mock_response: MockTokenResponse) -> Self {
+        let mut token_response = ProviderTokenResponse::new(
+            AccessToken::new(mock_response.access_token),
+            BasicTokenType::Bearer,
+            IdTokenFields { id_token: None },
+        );
+
+        token_response.set_expires_in(Some(&Duration::from_secs(mock_response.expires_in)));
+        token_response.set_refresh_token(mock_response.refresh_token.map(RefreshToken::new));
+        token_response
+    }
+}
+
+/// Exchanges an authorization code or a refresh token for an access token.
+async fn token(
+    Extension(state): Extension<Arc<Mutex<MockState>>>,
+    TypedHeader(Authorization(credentials)): TypedHeader<Authorization<Basic>>,
+    Form(form): Form<TokenRequest>,
+) -> impl IntoResponse {
+    if credentials.username() != MOCK_CLIENT_ID || credentials.password() != MOCK_CLIENT_SECRET {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let mut state = state.lock().unwrap();
+
+    let token_response = match form.grant_type.as_str() {
+        "authorization_code" => match (form.code, form.code_verifier, form.redirect_uri) {
+            (Some(code), Some(code_verifier), Some(redirect_uri)) => {
+                state.exchange_code(&code, &code_verifier, &redirect_uri)
+            }
+            _ => None,
+        },
+        "refresh_token" => form
+            .refresh_token
+            .and_then(|x| state.exchange_refresh_token(&x)),
+        _ => None,
+    };
+
+    match token_response {
+        Some(token_response) => Json(token_response).into_response(),
+        None => StatusCode::BAD_REQUEST.into_response(),
+    }
+}
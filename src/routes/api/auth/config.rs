@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use oauth2::{Scope, url::form_urlencoded};
+
+use crate::models::AuthProvider;
+
+/// Config file used if `AUTH_CONFIG_FILE` is not set, it is optional.
+const DEFAULT_CONFIG_FILE: &str = "auth.toml";
+
+/// Authorization parameters set by the login flow, these cannot be configured.
+const RESERVED_AUTH_PARAMS: &[&str] = &[
+    "response_type",
+    "client_id",
+    "redirect_uri",
+    "scope",
+    "state",
+    "nonce",
+    "code_challenge",
+    "code_challenge_method",
+];
+
+/// Overrides of the provider endpoints, scopes and authorization parameters.
+///
+/// The endpoints not set use the provider defaults, the scopes are requested in addition to the
+/// provider ones.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderConfig {
+    pub auth_url: Option<String>,
+    pub token_url: Option<String>,
+    pub userinfo_url: Option<String>,
+    pub revocation_url: Option<String>,
+    /// Base url of the provider REST API, only used by Github.
+    pub api_url: Option<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub auth_params: HashMap<String, String>,
+}
+
+impl ProviderConfig {
+    pub fn scopes(&self) -> Vec<Scope> {
+        self.scopes
+            .iter()
+            .map(|scope| Scope::new(scope.to_owned()))
+            .collect()
+    }
+
+    /// Overrides the config with the `{PROVIDER}_AUTH_URL`, `{PROVIDER}_TOKEN_URL`,
+    /// `{PROVIDER}_USERINFO_URL`, `{PROVIDER}_REVOCATION_URL`, `{PROVIDER}_API_URL`,
+    /// `{PROVIDER}_SCOPES` and `{PROVIDER}_AUTH_PARAMS` variables read with `env`.
+    fn merge_env(&mut self, provider: AuthProvider, env: impl Fn(&str) -> Option<String>) {
+        let env_prefix = provider.to_string().to_uppercase();
+        let get_var = |name: &str| env(&format!("{env_prefix}_{name}")).filter(|x| !x.is_empty());
+
+        let endpoints = [
+            ("AUTH_URL", &mut self.auth_url),
+            ("TOKEN_URL", &mut self.token_url),
+            ("USERINFO_URL", &mut self.userinfo_url),
+            ("REVOCATION_URL", &mut self.revocation_url),
+            ("API_URL", &mut self.api_url),
+        ];
+
+        for (name, endpoint) in endpoints {
+            if let Some(value) = get_var(name) {
+                *endpoint = Some(value);
+            }
+        }
+
+        // Scopes are separated by spaces like in the authorization request: `read:org repo`
+        if let Some(scopes) = get_var("SCOPES") {
+            self.scopes = scopes.split_whitespace().map(|x| x.to_owned()).collect();
+        }
+
+        // Parameters are url encoded: `prompt=consent&hd=example.com`
+        if let Some(auth_params) = get_var("AUTH_PARAMS") {
+            self.auth_params = form_urlencoded::parse(auth_params.as_bytes())
+                .into_owned()
+                .collect();
+        }
+    }
+
+    fn validate(&self, provider: AuthProvider) -> Result<(), anyhow::Error> {
+        for name in self.auth_params.keys() {
+            if RESERVED_AUTH_PARAMS.contains(&name.as_str()) {
+                anyhow::bail!("The '{name}' authorization parameter of {provider} cannot be set");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The providers config, loaded from the `AUTH_CONFIG_FILE` TOML file with a table for each
+/// provider:
+///
+/// ```toml
+/// [github]
+/// auth_url = "https://github.example.com/login/oauth/authorize"
+/// token_url = "https://github.example.com/login/oauth/access_token"
+/// api_url = "https://github.example.com/api/v3"
+/// scopes = ["read:org"]
+///
+/// [google.auth_params]
+/// prompt = "consent"
+/// hd = "example.com"
+/// ```
+#[derive(Debug, Default)]
+pub struct AuthConfig {
+    providers: HashMap<String, ProviderConfig>,
+}
+
+impl AuthConfig {
+    /// Loads the config file, if `AUTH_CONFIG_FILE` is not set `auth.toml` is used if exists.
+    pub fn load() -> Result<Self, anyhow::Error> {
+        let (path, required) = match std::env::var("AUTH_CONFIG_FILE") {
+            Ok(path) if !path.is_empty() => (path, true),
+            _ => (DEFAULT_CONFIG_FILE.to_owned(), false),
+        };
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if !required && err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(AuthConfig::default());
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read the auth config '{path}'"));
+            }
+        };
+
+        let config = AuthConfig::parse(&contents, &path)?;
+        tracing::debug!("Loaded auth config from '{path}'");
+        Ok(config)
+    }
+
+    fn parse(contents: &str, path: &str) -> Result<Self, anyhow::Error> {
+        let providers = toml::from_str::<HashMap<String, ProviderConfig>>(contents)
+            .with_context(|| format!("Invalid auth config '{path}'"))?;
+
+        for name in providers.keys() {
+            if matches!(AuthProvider::from(name.clone()), AuthProvider::Unknown(..)) {
+                anyhow::bail!("Unknown provider '{name}' in the auth config '{path}'");
+            }
+        }
+
+        Ok(AuthConfig { providers })
+    }
+
+    /// Returns the config of the given provider, the environment variables take precedence over
+    /// the config file.
+    pub fn provider(&self, provider: AuthProvider) -> Result<ProviderConfig, anyhow::Error> {
+        self.provider_with_env(provider, |name| std::env::var(name).ok())
+    }
+
+    fn provider_with_env(
+        &self,
+        provider: AuthProvider,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<ProviderConfig, anyhow::Error> {
+        let mut config = self
+            .providers
+            .get(&provider.to_string())
+            .cloned()
+            .unwrap_or_default();
+
+        config.merge_env(provider, env);
+        config.validate(provider)?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [github]
+        auth_url = "https://github.example.com/login/oauth/authorize"
+        api_url = "https://github.example.com/api/v3"
+        scopes = ["read:org"]
+
+        [google.auth_params]
+        prompt = "consent"
+    "#;
+
+    fn env<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn file_config_is_used_without_env() {
+        let config = AuthConfig::parse(CONFIG, "auth.toml").unwrap();
+
+        let github = config
+            .provider_with_env(AuthProvider::Github, env(&[]))
+            .unwrap();
+        assert_eq!(
+            github.auth_url.as_deref(),
+            Some("https://github.example.com/login/oauth/authorize")
+        );
+        assert_eq!(
+            github.api_url.as_deref(),
+            Some("https://github.example.com/api/v3")
+        );
+        assert_eq!(github.token_url, None);
+        assert_eq!(github.scopes, ["read:org"]);
+
+        let google = config
+            .provider_with_env(AuthProvider::Google, env(&[]))
+            .unwrap();
+        assert_eq!(google.auth_params["prompt"], "consent");
+
+        let discord = config
+            .provider_with_env(AuthProvider::Discord, env(&[]))
+            .unwrap();
+        assert_eq!(discord.auth_url, None);
+        assert!(discord.scopes.is_empty());
+    }
+
+    #[test]
+    fn env_overrides_file_config() {
+        let config = AuthConfig::parse(CONFIG, "auth.toml").unwrap();
+        let vars = [
+            ("GITHUB_AUTH_URL", "https://github.test/authorize"),
+            ("GITHUB_TOKEN_URL", "https://github.test/token"),
+            ("GITHUB_API_URL", ""),
+            ("GITHUB_SCOPES", "read:org  repo"),
+            (
+                "GOOGLE_AUTH_PARAMS",
+                "hd=example.com&login_hint=a%40example.com",
+            ),
+        ];
+
+        let github = config
+            .provider_with_env(AuthProvider::Github, env(&vars))
+            .unwrap();
+        assert_eq!(
+            github.auth_url.as_deref(),
+            Some("https://github.test/authorize")
+        );
+        assert_eq!(
+            github.token_url.as_deref(),
+            Some("https://github.test/token")
+        );
+        // Empty variables are ignored
+        assert_eq!(
+            github.api_url.as_deref(),
+            Some("https://github.example.com/api/v3")
+        );
+        assert_eq!(github.scopes, ["read:org", "repo"]);
+
+        // The parameters replace the ones of the file
+        let google = config
+            .provider_with_env(AuthProvider::Google, env(&vars))
+            .unwrap();
+        assert_eq!(
+            google.auth_params,
+            HashMap::from([
+                ("hd".to_owned(), "example.com".to_owned()),
+                ("login_hint".to_owned(), "a@example.com".to_owned()),
+            ])
+        );
+    }
+
+    #[test]
+    fn reserved_auth_params_are_rejected() {
+        for name in ["state", "code_challenge", "redirect_uri"] {
+            let file = format!("[oidc.auth_params]\n{name} = \"value\"");
+            let config = AuthConfig::parse(&file, "auth.toml").unwrap();
+            assert!(
+                config
+                    .provider_with_env(AuthProvider::Oidc, env(&[]))
+                    .is_err()
+            );
+
+            let config = AuthConfig::default();
+            let auth_params = format!("{name}=value");
+            let vars = [("OIDC_AUTH_PARAMS", auth_params.as_str())];
+            assert!(
+                config
+                    .provider_with_env(AuthProvider::Oidc, env(&vars))
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_providers_are_rejected() {
+        assert!(AuthConfig::parse("[gitlab]\nscopes = []", "auth.toml").is_err());
+    }
+}
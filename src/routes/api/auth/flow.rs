@@ -0,0 +1,658 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::{
+    Extension, Json, Router,
+    extract::Query,
+    http::StatusCode,
+    middleware,
+    response::{IntoResponse, Redirect},
+    routing::{get, post},
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use oauth2::{
+    AuthorizationCode, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, url::form_urlencoded,
+};
+use sqlx::SqlitePool;
+
+use super::{
+    provider::{OAuthProvider, ProviderRegistry},
+    tokens::ProviderTokens,
+};
+use crate::{
+    constants::{COOKIE_AUTH_BROWSER_ID, OAUTH_FLOW_DURATION},
+    misc::{error::AppError, redirect_target_or_root, safe_redirect_target},
+    models::{OAuthFlow, User},
+    routes::pages::LoginFailedTemplate,
+    server::{
+        ClientInfo, CsrfToken as FormCsrfToken, CurrentUser, PrivateCookies, SessionCache,
+        SessionConfig, UserTheme, session_cookie, verify_csrf,
+    },
+};
+
+/// Mounts the `login` and `callback` routes of the given provider.
+pub fn provider_router(provider: Arc<dyn OAuthProvider>) -> Router {
+    let kind = provider.kind();
+
+    // The provider routes are called by other sites, like the mock provider endpoints
+    Router::new()
+        .route(&format!("/api/auth/{kind}/login"), get(login))
+        .route(&format!("/api/auth/{kind}/callback"), get(callback))
+        .route(&format!("/api/auth/{kind}/profile"), get(profile))
+        .route(&format!("/api/auth/{kind}/unlink"), post(unlink))
+        .route_layer(middleware::from_fn(verify_csrf))
+        .merge(provider.router())
+        .layer(Extension(provider))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct LoginQuery {
+    return_to: Option<String>,
+    #[serde(default)]
+    remember_me: bool,
+    /// Links the identity to the signed in user instead of signing in with it.
+    #[serde(default)]
+    link: bool,
+}
+
+async fn login(
+    cookies: PrivateCookies,
+    Extension(provider): Extension<Arc<dyn OAuthProvider>>,
+    Extension(pool): Extension<SqlitePool>,
+    Query(query): Query<LoginQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let client = provider.client_settings().await?.into_client();
+
+    let (pkce_code_challenge, pkce_code_verifier) = PkceCodeChallenge::new_random_sha256();
+
+    // The nonce binds the id token to this login, providers without id tokens ignore it
+    let nonce = CsrfToken::new_random();
+
+    let config = provider.config();
+    let mut authorize_request = client
+        .authorize_url(CsrfToken::new_random)
+        .add_scopes(provider.scopes())
+        .add_scopes(config.scopes())
+        .add_extra_param("nonce", nonce.secret())
+        .set_pkce_challenge(pkce_code_challenge);
+
+    // The configured parameters replace the provider ones with the same name
+    for (name, value) in provider.auth_params() {
+        if !config.auth_params.contains_key(name) {
+            authorize_request = authorize_request.add_extra_param(name, value);
+        }
+    }
+
+    for (name, value) in &config.auth_params {
+        authorize_request = authorize_request.add_extra_param(name, value);
+    }
+
+    let (authorize_url, csrf_state) = authorize_request.url();
+
+    // Identifies the browser that started the login, so the callback cannot be completed on
+    // other browser. It's shared by all the logins in progress of the browser.
+    let browser_id = cookies
+        .get(COOKIE_AUTH_BROWSER_ID)
+        .map(|x| x.value().to_owned())
+        .unwrap_or_else(|| CsrfToken::new_random().secret().to_owned());
+
+    if let Err(err) = crate::db::delete_expired_oauth_flows(&pool).await {
+        tracing::error!("failed to delete expired oauth flows: {err:#}");
+    }
+
+    // The page the user requested before login, we redirect there after the callback
+    let return_to = query.return_to.as_deref().and_then(safe_redirect_target);
+
+    crate::db::create_oauth_flow(
+        &pool,
+        csrf_state.secret().to_owned(),
+        provider.kind(),
+        pkce_code_verifier.secret().to_owned(),
+        nonce.secret().to_owned(),
+        return_to,
+        query.remember_me,
+        query.link,
+        browser_id.clone(),
+        OAUTH_FLOW_DURATION,
+    )
+    .await
+    .context("Failed to create oauth flow")?;
+
+    let browser_id_cookie: Cookie = Cookie::build((COOKIE_AUTH_BROWSER_ID, browser_id))
+        .http_only(true)
+        .path("/")
+        .same_site(SameSite::Lax)
+        .max_age(cookie::time::Duration::milliseconds(
+            OAUTH_FLOW_DURATION.as_millis() as i64,
+        ))
+        .into();
+
+    let cookies = cookies.jar().add(browser_id_cookie);
+    Ok((cookies, Redirect::to(authorize_url.as_str())))
+}
+
+// Checkout the error response on: https://datatracker.ietf.org/doc/html/rfc6749#section-4.1.2.1
+#[derive(Debug, serde::Deserialize)]
+struct AuthRequest {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn callback(
+    cookies: PrivateCookies,
+    current_user: Option<CurrentUser>,
+    UserTheme(theme): UserTheme,
+    FormCsrfToken(csrf_token): FormCsrfToken,
+    client_info: ClientInfo,
+    Extension(provider): Extension<Arc<dyn OAuthProvider>>,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(session_cache): Extension<SessionCache>,
+    Extension(session_config): Extension<SessionConfig>,
+    Extension(providers): Extension<ProviderRegistry>,
+    Extension(provider_tokens): Extension<ProviderTokens>,
+    Query(query): Query<AuthRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    // Each flow can only be used once, so an unknown state is either expired or replayed
+    let oauth_flow = match &query.state {
+        Some(state) => crate::db::take_oauth_flow(&pool, state)
+            .await
+            .context("Failed to get oauth flow")?,
+        None => None,
+    };
+
+    // The flow must have been started by this browser and for this provider
+    let browser_id = cookies.get(COOKIE_AUTH_BROWSER_ID);
+    let oauth_flow = oauth_flow.filter(|flow| {
+        flow.provider == provider.kind()
+            && browser_id
+                .as_ref()
+                .is_some_and(|x| x.value() == flow.browser_id)
+    });
+
+    let current_user = current_user.map(|CurrentUser(user)| user);
+
+    // Retry with the same options
+    let retry_url = retry_url(provider.as_ref(), oauth_flow.as_ref());
+    let login_failed = |user: Option<User>, message: String, description: Option<String>| {
+        LoginFailedTemplate::new(
+            theme.unwrap_or_default(),
+            user,
+            csrf_token,
+            provider.display_name(),
+            retry_url,
+            message,
+            description,
+        )
+        .into_response()
+    };
+
+    if let Some(error) = query.error {
+        // Only the responses of a login of this browser can show the provider message on our page
+        let (message, description) = match (&oauth_flow, error.as_str()) {
+            (None, _) => ("The login could not be completed.".to_owned(), None),
+            (Some(_), "access_denied") => (
+                "The login was cancelled.".to_owned(),
+                query.error_description,
+            ),
+            (Some(_), _) => (
+                format!("{} returned an error: {error}", provider.display_name()),
+                query.error_description,
+            ),
+        };
+
+        tracing::warn!("{} login failed: {error}", provider.kind());
+        return Ok(login_failed(current_user, message, description));
+    }
+
+    let (Some(code), Some(oauth_flow)) = (query.code, oauth_flow) else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+
+    let http_client = providers.http_client();
+
+    let code = AuthorizationCode::new(code);
+    let pkce_code_verifier = PkceCodeVerifier::new(oauth_flow.pkce_code_verifier);
+
+    let token_response = provider
+        .exchange_code(http_client, code, pkce_code_verifier)
+        .await?;
+
+    // Get the provider user info
+    let profile = provider
+        .profile_from_token(http_client, &token_response, &oauth_flow.nonce)
+        .await?;
+
+    let existing_user =
+        crate::db::get_user_by_account_id(&pool, provider.kind(), profile.account_id.clone())
+            .await
+            .context("Failed to get user")?;
+
+    // The user started the login from the account page to link a new identity
+    if oauth_flow.link {
+        let Some(current_user) = current_user else {
+            let message = "You must be signed in to link an account.".to_owned();
+            return Ok(login_failed(None, message, None));
+        };
+
+        match existing_user {
+            Some(user) if user.id != current_user.id => {
+                tracing::warn!(
+                    "{} account '{}' is already linked to other user",
+                    provider.kind(),
+                    profile.account_id
+                );
+
+                let message = format!(
+                    "This {} account is already linked to another user.",
+                    provider.display_name()
+                );
+                return Ok(login_failed(Some(current_user), message, None));
+            }
+            Some(_) => {}
+            None => {
+                let identities = crate::db::get_user_identities(&pool, current_user.id)
+                    .await
+                    .context("Failed to get user identities")?;
+
+                // Only one account of each provider can be linked
+                if identities.iter().any(|x| x.provider == provider.kind()) {
+                    let message = format!(
+                        "Your account is already linked to another {} account, remove it first.",
+                        provider.display_name()
+                    );
+                    return Ok(login_failed(Some(current_user), message, None));
+                }
+
+                crate::db::create_user_identity(
+                    &pool,
+                    current_user.id,
+                    provider.kind(),
+                    profile.account_id,
+                )
+                .await
+                .context("Failed to link user identity")?;
+            }
+        }
+
+        provider_tokens
+            .save_token_response(&pool, current_user.id, provider.kind(), &token_response)
+            .await
+            .context("Failed to save user tokens")?;
+
+        return Ok(Redirect::to("/account").into_response());
+    }
+
+    // Add user session, the profile of existing users is updated as it may have changed
+    let user = match existing_user {
+        Some(x) => crate::db::update_user_profile(
+            &pool,
+            x.id,
+            profile.username,
+            profile.image_url,
+            profile.email,
+            profile.email_verified,
+        )
+        .await
+        .inspect(|user| session_cache.invalidate_user(user.id))
+        .context("Failed to update user")?,
+        None => crate::db::create_user(
+            &pool,
+            profile.account_id,
+            provider.kind(),
+            profile.username,
+            profile.image_url,
+            profile.email,
+            profile.email_verified,
+        )
+        .await
+        .context("Failed to create user")?,
+    };
+
+    provider_tokens
+        .save_token_response(&pool, user.id, provider.kind(), &token_response)
+        .await
+        .context("Failed to save user tokens")?;
+
+    let remember_me = oauth_flow.remember_me;
+    let (user_session, session_token) = crate::db::create_user_session(
+        &pool,
+        user.id,
+        remember_me,
+        session_config.idle_timeout(remember_me),
+        session_config.max_lifetime(remember_me),
+        client_info.user_agent,
+        client_info.ip_address,
+    )
+    .await
+    .context("Failed to create user session")?;
+
+    let session_cookie = session_cookie(&user_session, session_token);
+
+    let return_to = redirect_target_or_root(oauth_flow.return_to.as_deref());
+
+    let response = (
+        CookieJar::new().add(session_cookie),
+        Redirect::to(&return_to),
+    )
+        .into_response();
+    Ok(response)
+}
+
+/// Returns the url that starts a new login with the same options as the failed one.
+fn retry_url(provider: &dyn OAuthProvider, oauth_flow: Option<&OAuthFlow>) -> String {
+    let mut retry_query = form_urlencoded::Serializer::new(String::new());
+    if let Some(oauth_flow) = oauth_flow {
+        if let Some(return_to) = &oauth_flow.return_to {
+            retry_query.append_pair("return_to", return_to);
+        }
+
+        if oauth_flow.remember_me {
+            retry_query.append_pair("remember_me", "true");
+        }
+
+        if oauth_flow.link {
+            retry_query.append_pair("link", "true");
+        }
+    }
+
+    let retry_query = retry_query.finish();
+    match retry_query.is_empty() {
+        true => format!("/api/auth/{}/login", provider.kind()),
+        false => format!("/api/auth/{}/login?{retry_query}", provider.kind()),
+    }
+}
+
+/// Returns the current profile of the user in the provider, this requests the provider with the
+/// stored access token.
+async fn profile(
+    CurrentUser(user): CurrentUser,
+    Extension(provider): Extension<Arc<dyn OAuthProvider>>,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(providers): Extension<ProviderRegistry>,
+    Extension(provider_tokens): Extension<ProviderTokens>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(access_token) = provider_tokens
+        .get_access_token(&pool, provider.as_ref(), user.id)
+        .await?
+    else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let profile = provider
+        .fetch_profile(providers.http_client(), &access_token)
+        .await?;
+    Ok(Json(profile).into_response())
+}
+
+/// Removes the provider identity of the user, the last identity cannot be removed.
+async fn unlink(
+    CurrentUser(user): CurrentUser,
+    Extension(provider): Extension<Arc<dyn OAuthProvider>>,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(provider_tokens): Extension<ProviderTokens>,
+) -> Result<impl IntoResponse, AppError> {
+    let identities = crate::db::get_user_identities(&pool, user.id)
+        .await
+        .context("Failed to get user identities")?;
+
+    if !identities.iter().any(|x| x.provider == provider.kind()) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    if identities.len() == 1 {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    // A failed revocation is recorded but must not prevent the user from removing the identity
+    if let Err(err) = provider_tokens
+        .revoke_user_token(&pool, provider.as_ref(), user.id)
+        .await
+    {
+        tracing::error!("failed to revoke {} token: {err:#}", provider.kind());
+    }
+
+    crate::db::delete_user_identity(&pool, user.id, provider.kind())
+        .await
+        .context("Failed to remove user identity")?;
+
+    Ok(Redirect::to("/account").into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::Once;
+
+    use oauth2::url::Url;
+    use reqwest::{StatusCode, header};
+    use sqlx::sqlite::SqliteConnectOptions;
+
+    use super::*;
+    use crate::constants::COOKIE_AUTH_SESSION;
+    use crate::routes::{AvatarCache, ImageProxy};
+    use crate::server::CookieKeys;
+
+    /// The app runs on a random port, this url is only used in the redirects and is never
+    /// requested, the mock provider completes the login in process.
+    const BASE_URL: &str = "http://app.invalid";
+
+    fn set_base_url() {
+        static SET_BASE_URL: Once = Once::new();
+
+        // SAFETY: all the tests set the same value, and the environment is only accessed through
+        // `std::env`, which synchronizes the reads and writes.
+        SET_BASE_URL.call_once(|| unsafe { std::env::set_var("BASE_URL", BASE_URL) });
+    }
+
+    /// A browser of the app running with the mock provider on a new database.
+    struct TestClient {
+        addr: SocketAddr,
+        http_client: reqwest::Client,
+        cookies: HashMap<String, String>,
+        pool: SqlitePool,
+        dir: PathBuf,
+    }
+
+    impl TestClient {
+        async fn start() -> Self {
+            set_base_url();
+
+            let dir = std::env::temp_dir().join(format!("app-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let connect_options = SqliteConnectOptions::new()
+                .filename(dir.join("app.db"))
+                .create_if_missing(true);
+            let pool = SqlitePool::connect_with(connect_options).await.unwrap();
+            sqlx::migrate!().run(&pool).await.unwrap();
+
+            let image_proxy = ImageProxy::from_env().unwrap();
+            let app = crate::app(
+                pool.clone(),
+                ProviderRegistry::mock().unwrap(),
+                CookieKeys::from_env().unwrap(),
+                SessionConfig::from_env().unwrap(),
+                image_proxy.clone(),
+                AvatarCache::new(dir.join("avatars"), image_proxy).unwrap(),
+            );
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+            let http_client = reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap();
+
+            TestClient {
+                addr,
+                http_client,
+                cookies: HashMap::new(),
+                pool,
+                dir,
+            }
+        }
+
+        async fn get(&mut self, path: &str) -> reqwest::Response {
+            let request = self.http_client.get(format!("http://{}{path}", self.addr));
+            self.send(request).await
+        }
+
+        async fn post_form(&mut self, path: &str, form: &[(&str, &str)]) -> reqwest::Response {
+            let request = self
+                .http_client
+                .post(format!("http://{}{path}", self.addr))
+                .form(form);
+            self.send(request).await
+        }
+
+        async fn send(&mut self, request: reqwest::RequestBuilder) -> reqwest::Response {
+            let cookie = self
+                .cookies
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect::<Vec<_>>()
+                .join("; ");
+
+            let response = request.header(header::COOKIE, cookie).send().await.unwrap();
+
+            for set_cookie in response.headers().get_all(header::SET_COOKIE) {
+                let cookie = Cookie::parse(set_cookie.to_str().unwrap().to_owned()).unwrap();
+                match cookie.max_age() {
+                    Some(max_age) if max_age.is_zero() => self.cookies.remove(cookie.name()),
+                    _ => self
+                        .cookies
+                        .insert(cookie.name().to_owned(), cookie.value().to_owned()),
+                };
+            }
+
+            response
+        }
+
+        /// Starts a mock login and returns the url of the authorization page.
+        async fn start_login(&mut self, query: &str) -> Url {
+            let response = self.get(&format!("/api/auth/mock/login{query}")).await;
+            assert_eq!(response.status(), StatusCode::SEE_OTHER);
+            Url::parse(location(&response)).unwrap()
+        }
+
+        /// Picks the identity in the authorization page and returns the path of the callback.
+        async fn authorize(&mut self, authorize_url: &Url, account_id: &str) -> String {
+            let query: HashMap<_, _> = authorize_url.query_pairs().collect();
+            let form = [
+                ("client_id", query["client_id"].as_ref()),
+                ("redirect_uri", query["redirect_uri"].as_ref()),
+                ("state", query["state"].as_ref()),
+                ("code_challenge", query["code_challenge"].as_ref()),
+                ("account_id", account_id),
+                ("username", "Test User"),
+                ("email", "test@example.com"),
+            ];
+
+            let response = self.post_form("/api/auth/mock/authorize", &form).await;
+            assert_eq!(response.status(), StatusCode::SEE_OTHER);
+            path_and_query(location(&response))
+        }
+
+        async fn count(&self, table: &str) -> i64 {
+            sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+                .fetch_one(&self.pool)
+                .await
+                .unwrap()
+        }
+    }
+
+    impl Drop for TestClient {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+
+    fn location(response: &reqwest::Response) -> &str {
+        response.headers()[header::LOCATION].to_str().unwrap()
+    }
+
+    /// Returns the path of an url of the app, the app is not served on `BASE_URL`.
+    fn path_and_query(url: &str) -> String {
+        let url = Url::parse(url).unwrap();
+        assert_eq!(url.origin(), Url::parse(BASE_URL).unwrap().origin());
+
+        match url.query() {
+            Some(query) => format!("{}?{query}", url.path()),
+            None => url.path().to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn mock_login_creates_a_user_session() {
+        let mut client = TestClient::start().await;
+
+        let authorize_url = client.start_login("?return_to=/account").await;
+        assert_eq!(authorize_url.path(), "/api/auth/mock/authorize");
+
+        let callback = client.authorize(&authorize_url, "mock-test").await;
+        let response = client.get(&callback).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(location(&response), "/account");
+        assert!(client.cookies.contains_key(COOKIE_AUTH_SESSION));
+
+        let response = client.get("/api/auth/me").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.text().await.unwrap().contains("Test User"));
+
+        // The stored access token gets the profile from the provider
+        let response = client.get("/api/auth/mock/profile").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.text().await.unwrap().contains("mock-test"));
+
+        assert_eq!(client.count("user").await, 1);
+        assert_eq!(client.count("user_session").await, 1);
+        assert_eq!(client.count("user_token").await, 1);
+        assert_eq!(client.count("oauth_flow").await, 0);
+    }
+
+    #[tokio::test]
+    async fn provider_errors_render_the_login_failed_page() {
+        let mut client = TestClient::start().await;
+
+        let authorize_url = client.start_login("?return_to=/account").await;
+        let query: HashMap<_, _> = authorize_url.query_pairs().collect();
+        let callback = format!(
+            "/api/auth/mock/callback?error=access_denied&error_description=The+user+denied+access&state={}",
+            query["state"]
+        );
+
+        let response = client.get(&callback).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("The login was cancelled."));
+        assert!(body.contains("The user denied access"));
+        assert!(body.contains(r#"href="/api/auth/mock/login?return_to=%2Faccount""#));
+
+        // The flow cannot be completed after the error
+        assert_eq!(client.count("oauth_flow").await, 0);
+        assert_eq!(client.count("user_session").await, 0);
+    }
+
+    #[tokio::test]
+    async fn replayed_callbacks_are_rejected() {
+        let mut client = TestClient::start().await;
+
+        let authorize_url = client.start_login("").await;
+        let callback = client.authorize(&authorize_url, "mock-test").await;
+
+        let response = client.get(&callback).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let response = client.get(&callback).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(client.count("user_session").await, 1);
+    }
+}
@@ -0,0 +1,199 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation, decode, decode_header,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+};
+use serde::de::DeserializeOwned;
+use tokio::sync::RwLock;
+
+/// Minimum time between two JWKS requests, prevents tokens with random `kid` from making us
+/// request the keys on each callback.
+const JWKS_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The claims we always check on an id token.
+pub trait IdTokenClaims: DeserializeOwned {
+    fn nonce(&self) -> Option<&str>;
+}
+
+/// A cache of the JSON Web Key Set used to sign the id tokens of a provider.
+///
+/// The keys are requested the first time a token is verified and requested again when a token
+/// is signed with an unknown key, which happens when the provider rotates its keys.
+pub struct JwksCache {
+    jwks_uri: String,
+    http_client: reqwest::Client,
+    state: RwLock<JwksState>,
+}
+
+struct JwksState {
+    keys: JwkSet,
+    fetched_at: Option<Instant>,
+}
+
+impl JwksCache {
+    pub fn new(jwks_uri: impl Into<String>, http_client: reqwest::Client) -> Self {
+        JwksCache {
+            jwks_uri: jwks_uri.into(),
+            http_client,
+            state: RwLock::new(JwksState {
+                keys: JwkSet { keys: Vec::new() },
+                fetched_at: None,
+            }),
+        }
+    }
+
+    async fn jwk(&self, kid: &str) -> Result<Jwk, anyhow::Error> {
+        {
+            let state = self.state.read().await;
+            if let Some(jwk) = state.keys.find(kid) {
+                return Ok(jwk.clone());
+            }
+        }
+
+        let mut state = self.state.write().await;
+
+        // Other request may have refreshed the keys while we waited for the lock
+        if state.keys.find(kid).is_none() {
+            let can_refresh = state
+                .fetched_at
+                .is_none_or(|fetched_at| fetched_at.elapsed() >= JWKS_MIN_REFRESH_INTERVAL);
+
+            if !can_refresh {
+                anyhow::bail!("Unknown JSON Web Key '{kid}'");
+            }
+
+            tracing::debug!("Unknown JSON Web Key '{kid}', requesting {}", self.jwks_uri);
+            state.keys = fetch_jwks(&self.http_client, &self.jwks_uri).await?;
+            state.fetched_at = Some(Instant::now());
+        }
+
+        state
+            .keys
+            .find(kid)
+            .cloned()
+            .with_context(|| format!("Unknown JSON Web Key '{kid}'"))
+    }
+
+    /// Verifies the signature, issuer, audience, expiration and nonce of the given id token
+    /// and returns its claims.
+    pub async fn verify<C: IdTokenClaims>(
+        &self,
+        id_token: &str,
+        issuers: &[&str],
+        audience: &str,
+        nonce: &str,
+    ) -> Result<C, anyhow::Error> {
+        let header = decode_header(id_token).context("Invalid id token header")?;
+        let kid = header.kid.context("The id token has no 'kid'")?;
+
+        // The algorithm is chosen by the key, the token header cannot be trusted
+        let jwk = self.jwk(&kid).await?;
+        if !key_algorithms(&jwk).contains(&header.alg) {
+            anyhow::bail!(
+                "The id token algorithm {:?} does not match the key '{kid}'",
+                header.alg
+            );
+        }
+
+        let decoding_key = DecodingKey::from_jwk(&jwk).context("Invalid JSON Web Key")?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(issuers);
+        validation.set_audience(&[audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let claims = decode::<C>(id_token, &decoding_key, &validation)
+            .context("Invalid id token")?
+            .claims;
+
+        if claims.nonce() != Some(nonce) {
+            anyhow::bail!("The id token nonce does not match");
+        }
+
+        Ok(claims)
+    }
+}
+
+/// Returns the algorithms the key can verify, the one set on the key or else the ones of its
+/// key family.
+fn key_algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    if let Some(key_algorithm) = jwk.common.key_algorithm {
+        return Algorithm::from_str(&key_algorithm.to_string())
+            .into_iter()
+            .collect();
+    }
+
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            _ => Vec::new(),
+        },
+        AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+        // Only asymmetric keys are published on a JWKS
+        AlgorithmParameters::OctetKey(_) => Vec::new(),
+    }
+}
+
+async fn fetch_jwks(
+    http_client: &reqwest::Client,
+    jwks_uri: &str,
+) -> Result<JwkSet, anyhow::Error> {
+    let jwks = http_client
+        .get(jwks_uri)
+        .send()
+        .await
+        .context("Failed to get the JSON Web Key Set")?
+        .error_for_status()
+        .context("Failed to get the JSON Web Key Set")?
+        .json::<JwkSet>()
+        .await
+        .context("Failed to convert the JSON Web Key Set to Json")?;
+
+    Ok(jwks)
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::jwk::{CommonParameters, KeyAlgorithm, RSAKeyParameters, RSAKeyType};
+
+    use super::*;
+
+    fn rsa_jwk(key_algorithm: Option<KeyAlgorithm>) -> Jwk {
+        Jwk {
+            common: CommonParameters {
+                key_algorithm,
+                ..Default::default()
+            },
+            algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: "n".to_owned(),
+                e: "AQAB".to_owned(),
+            }),
+        }
+    }
+
+    #[test]
+    fn key_algorithm_is_pinned_by_the_key() {
+        assert_eq!(
+            key_algorithms(&rsa_jwk(Some(KeyAlgorithm::RS256))),
+            vec![Algorithm::RS256]
+        );
+
+        let rsa_algorithms = key_algorithms(&rsa_jwk(None));
+        assert!(rsa_algorithms.contains(&Algorithm::PS512));
+        assert!(!rsa_algorithms.contains(&Algorithm::HS256));
+        assert!(!rsa_algorithms.contains(&Algorithm::ES256));
+    }
+}
@@ -1,21 +1,53 @@
-use self::{
-    auth_discord::discord_auth_router, auth_github::github_auth_router,
-    auth_google::google_auth_router,
+use crate::{
+    constants::COOKIE_AUTH_SESSION,
+    models::{AuthProvider, User},
+    routes::pages::{AvatarCache, LogoutTemplate},
+    server::{CsrfToken, CurrentUser, SessionCache, UserTheme, verify_csrf},
 };
-use crate::constants::COOKIE_AUTH_SESSION;
+use anyhow::Context;
 use axum::{
     Extension, Json, Router,
     http::StatusCode,
+    middleware,
     response::{ErrorResponse, IntoResponse, Redirect},
-    routing::get,
+    routing::{get, post},
 };
 use axum_extra::extract::cookie::CookieJar;
 use cookie::Cookie;
+use oauth2::{
+    AuthUrl, Client, ClientId, ClientSecret, EndpointNotSet, EndpointSet, RedirectUrl,
+    RevocationUrl, StandardRevocableToken, TokenUrl,
+    basic::{BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse},
+};
 use sqlx::SqlitePool;
 
 mod auth_discord;
 mod auth_github;
 mod auth_google;
+mod auth_mock;
+mod auth_oidc;
+mod config;
+mod flow;
+mod id_token;
+mod provider;
+mod sessions;
+mod tokens;
+
+pub use provider::ProviderRegistry;
+pub use tokens::ProviderTokens;
+
+type OAuthClient = Client<
+    BasicErrorResponse,
+    provider::ProviderTokenResponse,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointSet,
+>;
 
 pub struct ClientSettings {
     client_id: oauth2::ClientId,
@@ -23,20 +55,101 @@ pub struct ClientSettings {
     auth_url: oauth2::AuthUrl,
     token_url: oauth2::TokenUrl,
     redirect_url: oauth2::RedirectUrl,
+    revocation_url: Option<oauth2::RevocationUrl>,
 }
 
-pub fn auth_router() -> Router {
-    Router::new()
+impl ClientSettings {
+    /// Creates the settings of the given provider, the client id and secret are read from
+    /// the `{PROVIDER}_CLIENT_ID` and `{PROVIDER}_CLIENT_SECRET` environment variables.
+    pub fn from_env(
+        provider: AuthProvider,
+        auth_url: &str,
+        token_url: &str,
+    ) -> Result<Self, anyhow::Error> {
+        let env_prefix = provider.to_string().to_uppercase();
+
+        let client_id_var = format!("{env_prefix}_CLIENT_ID");
+        let client_id = ClientId::new(
+            std::env::var(&client_id_var)
+                .with_context(|| format!("Missing the {client_id_var} environment variable"))?,
+        );
+
+        let client_secret_var = format!("{env_prefix}_CLIENT_SECRET");
+        let client_secret =
+            ClientSecret::new(std::env::var(&client_secret_var).with_context(|| {
+                format!("Missing the {client_secret_var} environment variable")
+            })?);
+
+        ClientSettings::new(provider, client_id, client_secret, auth_url, token_url)
+    }
+
+    /// Creates the settings of the given provider, the redirect url is the provider callback
+    /// under `BASE_URL`.
+    pub fn new(
+        provider: AuthProvider,
+        client_id: ClientId,
+        client_secret: ClientSecret,
+        auth_url: &str,
+        token_url: &str,
+    ) -> Result<Self, anyhow::Error> {
+        let auth_url =
+            AuthUrl::new(auth_url.to_string()).context("Invalid authorization endpoint URL")?;
+        let token_url =
+            TokenUrl::new(token_url.to_string()).context("Invalid token endpoint URL")?;
+
+        let base_url = std::env::var("BASE_URL").context("Failed to get app base url")?;
+        let redirect_url = RedirectUrl::new(format!("{base_url}/api/auth/{provider}/callback"))
+            .context("Invalid redirect url")?;
+
+        Ok(ClientSettings {
+            client_id,
+            client_secret,
+            auth_url,
+            token_url,
+            redirect_url,
+            revocation_url: None,
+        })
+    }
+
+    /// Sets the RFC 7009 endpoint used to revoke the tokens.
+    pub fn with_revocation_url(mut self, revocation_url: &str) -> Result<Self, anyhow::Error> {
+        let revocation_url = RevocationUrl::new(revocation_url.to_string())
+            .context("Invalid revocation endpoint URL")?;
+
+        self.revocation_url = Some(revocation_url);
+        Ok(self)
+    }
+
+    fn into_client(self) -> OAuthClient {
+        Client::new(self.client_id)
+            .set_client_secret(self.client_secret)
+            .set_auth_uri(self.auth_url)
+            .set_token_uri(self.token_url)
+            // Set the URL the user will be redirected to after the authorization process.
+            .set_redirect_uri(self.redirect_url)
+    }
+}
+
+pub fn auth_router(providers: &ProviderRegistry) -> Router {
+    let mut router = Router::new()
         .route("/api/auth/me", get(me))
-        .route("/api/auth/logout", get(logout))
-        .merge(google_auth_router())
-        .merge(github_auth_router())
-        .merge(discord_auth_router())
+        .route("/api/auth/csrf", get(csrf))
+        .route("/api/auth/logout", get(logout_confirmation).post(logout))
+        .route("/api/auth/delete_account", post(delete_account))
+        .merge(sessions::sessions_router())
+        .route_layer(middleware::from_fn(verify_csrf));
+
+    for provider in providers.iter() {
+        router = router.merge(flow::provider_router(provider.clone()));
+    }
+
+    router
 }
 
 pub async fn me(
     cookies: CookieJar,
     Extension(pool): Extension<SqlitePool>,
+    Extension(session_cache): Extension<SessionCache>,
 ) -> Result<impl IntoResponse, ErrorResponse> {
     let session_cookie = cookies.get(COOKIE_AUTH_SESSION);
 
@@ -44,19 +157,51 @@ pub async fn me(
         return Err(ErrorResponse::from(StatusCode::UNAUTHORIZED));
     };
 
-    let user = crate::db::get_user_by_session_id(&pool, session_cookie.value())
+    let user = session_cache
+        .get(&pool, session_cookie.value())
         .await
         .map_err(|_| ErrorResponse::from(StatusCode::INTERNAL_SERVER_ERROR))?;
 
     match user {
-        Some(user) => Ok(Json(user).into_response()),
+        Some((_, user)) => Ok(Json(user).into_response()),
         None => Err(ErrorResponse::from(StatusCode::NOT_FOUND)),
     }
 }
 
+#[derive(Debug, serde::Serialize)]
+struct CsrfResponse {
+    csrf_token: String,
+}
+
+/// Returns the CSRF token to send in the `X-CSRF-Token` header of the state-changing requests.
+pub async fn csrf(CsrfToken(csrf_token): CsrfToken) -> impl IntoResponse {
+    Json(CsrfResponse { csrf_token })
+}
+
+/// Asks the user to confirm the logout, so it cannot be triggered by a link or an image.
+pub async fn logout_confirmation(
+    current_user: Option<CurrentUser>,
+    UserTheme(theme): UserTheme,
+    CsrfToken(csrf_token): CsrfToken,
+) -> impl IntoResponse {
+    let Some(CurrentUser(user)) = current_user else {
+        return Redirect::to("/").into_response();
+    };
+
+    LogoutTemplate::new(theme.unwrap_or_default(), Some(user), csrf_token).into_response()
+}
+
+/// Logs out the current session.
+///
+/// The provider tokens are revoked only when this was the last session of the user, the other
+/// sessions still use them. The session is removed before the revocation, so the user is logged
+/// out even if it fails.
 pub async fn logout(
     mut cookies: CookieJar,
     Extension(pool): Extension<SqlitePool>,
+    Extension(session_cache): Extension<SessionCache>,
+    Extension(providers): Extension<ProviderRegistry>,
+    Extension(provider_tokens): Extension<ProviderTokens>,
 ) -> Result<impl IntoResponse, ErrorResponse> {
     let session_cookie = cookies.get(COOKIE_AUTH_SESSION);
 
@@ -64,10 +209,27 @@ pub async fn logout(
         return Err(ErrorResponse::from(StatusCode::UNAUTHORIZED));
     };
 
+    let user_session = session_cache
+        .get(&pool, session_cookie.value())
+        .await
+        .map_err(|_| ErrorResponse::from(StatusCode::INTERNAL_SERVER_ERROR))?;
+
     crate::db::delete_user_session(&pool, session_cookie.value())
         .await
         .map_err(|_| ErrorResponse::from(StatusCode::INTERNAL_SERVER_ERROR))?;
 
+    session_cache.invalidate_session(session_cookie.value());
+
+    if let Some((_, user)) = user_session {
+        match crate::db::get_user_sessions(&pool, user.id).await {
+            Ok(sessions) if sessions.is_empty() => {
+                revoke_provider_tokens(&pool, &providers, &provider_tokens, &user).await;
+            }
+            Ok(_) => {}
+            Err(err) => tracing::error!("failed to get user sessions: {err:#}"),
+        }
+    }
+
     let mut remove_session_cookie = Cookie::new(COOKIE_AUTH_SESSION, "");
     remove_session_cookie.set_path("/");
     remove_session_cookie.make_removal();
@@ -75,3 +237,61 @@ pub async fn logout(
     cookies = cookies.add(remove_session_cookie);
     Ok((cookies, Redirect::to("/")))
 }
+
+pub async fn delete_account(
+    CurrentUser(user): CurrentUser,
+    mut cookies: CookieJar,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(session_cache): Extension<SessionCache>,
+    Extension(providers): Extension<ProviderRegistry>,
+    Extension(avatar_cache): Extension<AvatarCache>,
+    Extension(provider_tokens): Extension<ProviderTokens>,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    revoke_provider_tokens(&pool, &providers, &provider_tokens, &user).await;
+
+    crate::db::delete_user(&pool, user.id)
+        .await
+        .map_err(|_| ErrorResponse::from(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    session_cache.invalidate_user(user.id);
+
+    if let Err(err) = avatar_cache.remove(user.id).await {
+        tracing::error!("failed to remove avatar of user '{}': {err:#}", user.id);
+    }
+
+    let mut remove_session_cookie = Cookie::new(COOKIE_AUTH_SESSION, "");
+    remove_session_cookie.set_path("/");
+    remove_session_cookie.make_removal();
+
+    cookies = cookies.add(remove_session_cookie);
+    Ok((cookies, Redirect::to("/login")))
+}
+
+// A failed revocation is recorded but must not prevent the user from logging out
+async fn revoke_provider_tokens(
+    pool: &SqlitePool,
+    providers: &ProviderRegistry,
+    provider_tokens: &ProviderTokens,
+    user: &User,
+) {
+    let identities = match crate::db::get_user_identities(pool, user.id).await {
+        Ok(x) => x,
+        Err(err) => {
+            tracing::error!("failed to get user identities: {err:#}");
+            return;
+        }
+    };
+
+    for identity in identities {
+        let Some(provider) = providers.get(identity.provider) else {
+            continue;
+        };
+
+        if let Err(err) = provider_tokens
+            .revoke_user_token(pool, provider.as_ref(), user.id)
+            .await
+        {
+            tracing::error!("failed to revoke {} token: {err:#}", identity.provider);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::Router;
+use oauth2::{
+    AccessToken, AuthorizationCode, ExtraTokenFields, PkceCodeVerifier, RefreshToken, Scope,
+    StandardRevocableToken, StandardTokenResponse, TokenResponse, basic::BasicTokenType,
+};
+
+use super::{
+    ClientSettings,
+    auth_discord::DiscordProvider,
+    auth_github::GithubProvider,
+    auth_google::GoogleProvider,
+    auth_mock::MockProvider,
+    auth_oidc::OidcProvider,
+    config::{AuthConfig, ProviderConfig},
+};
+use crate::constants::{PROVIDER_CONNECT_TIMEOUT, PROVIDER_TIMEOUT};
+use crate::models::{AuthProvider, UserToken};
+
+/// The user information returned by a provider, normalized for all providers.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ProviderProfile {
+    pub account_id: String,
+    pub username: String,
+    pub image_url: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: bool,
+}
+
+/// Token fields returned by OpenID Connect providers in addition to the OAuth ones.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct IdTokenFields {
+    pub id_token: Option<String>,
+}
+
+impl ExtraTokenFields for IdTokenFields {}
+
+pub type ProviderTokenResponse = StandardTokenResponse<IdTokenFields, BasicTokenType>;
+
+/// An OAuth provider the users can login with.
+///
+/// The login and callback flow is shared by all providers, an implementation only
+/// describes the endpoints, the scopes and how to get the user profile.
+#[async_trait::async_trait]
+pub trait OAuthProvider: Send + Sync {
+    /// The provider kind, this is also used as the provider path: `/api/auth/{provider}/login`.
+    fn kind(&self) -> AuthProvider;
+
+    /// The name displayed in the login page.
+    fn display_name(&self) -> String;
+
+    /// Logo displayed next to the name in the login page.
+    fn logo_url(&self) -> Option<&str> {
+        None
+    }
+
+    /// The configured overrides of the provider endpoints, scopes and authorization parameters.
+    fn config(&self) -> &ProviderConfig;
+
+    /// Returns the settings used to create the oauth client.
+    async fn client_settings(&self) -> Result<ClientSettings, anyhow::Error>;
+
+    /// The scopes requested when redirecting to the provider authorization page.
+    fn scopes(&self) -> Vec<Scope> {
+        Vec::new()
+    }
+
+    /// Additional parameters sent to the provider authorization page.
+    fn auth_params(&self) -> Vec<(&str, &str)> {
+        Vec::new()
+    }
+
+    /// Additional routes served by the provider itself, mounted next to the `login` and
+    /// `callback` routes.
+    fn router(&self) -> Router {
+        Router::new()
+    }
+
+    /// Exchanges the authorization code of the login callback for the user tokens.
+    ///
+    /// By default this requests the token endpoint of the client settings.
+    async fn exchange_code(
+        &self,
+        http_client: &reqwest::Client,
+        code: AuthorizationCode,
+        pkce_code_verifier: PkceCodeVerifier,
+    ) -> Result<ProviderTokenResponse, anyhow::Error> {
+        self.client_settings()
+            .await?
+            .into_client()
+            .exchange_code(code)
+            .set_pkce_verifier(pkce_code_verifier)
+            .request_async(http_client)
+            .await
+            .context("Failed to get token response")
+    }
+
+    /// Exchanges the refresh token for a new access token.
+    ///
+    /// By default this requests the token endpoint of the client settings.
+    async fn exchange_refresh_token(
+        &self,
+        http_client: &reqwest::Client,
+        refresh_token: &RefreshToken,
+    ) -> Result<ProviderTokenResponse, anyhow::Error> {
+        self.client_settings()
+            .await?
+            .into_client()
+            .exchange_refresh_token(refresh_token)
+            .request_async(http_client)
+            .await
+            .context("Failed to refresh the access token")
+    }
+
+    /// Gets the user profile using the given access token.
+    async fn fetch_profile(
+        &self,
+        http_client: &reqwest::Client,
+        access_token: &AccessToken,
+    ) -> Result<ProviderProfile, anyhow::Error>;
+
+    /// Gets the user profile from the token response of the login callback, `nonce` is the
+    /// value sent on login and must be checked if the provider returns an id token.
+    ///
+    /// By default this calls `fetch_profile` with the access token.
+    async fn profile_from_token(
+        &self,
+        http_client: &reqwest::Client,
+        token_response: &ProviderTokenResponse,
+        _nonce: &str,
+    ) -> Result<ProviderProfile, anyhow::Error> {
+        self.fetch_profile(http_client, token_response.access_token())
+            .await
+    }
+
+    /// Revokes the given user token in the provider.
+    ///
+    /// By default this revokes the refresh token, or the access token if there is none, with the
+    /// RFC 7009 revocation endpoint of the client settings.
+    async fn revoke_token(
+        &self,
+        http_client: &reqwest::Client,
+        user_token: &UserToken,
+    ) -> Result<(), anyhow::Error> {
+        let client_settings = self.client_settings().await?;
+        let revocation_url = client_settings
+            .revocation_url
+            .clone()
+            .with_context(|| format!("{} does not support token revocation", self.kind()))?;
+
+        let token = match &user_token.refresh_token {
+            Some(refresh_token) => {
+                StandardRevocableToken::RefreshToken(RefreshToken::new(refresh_token.clone()))
+            }
+            None => StandardRevocableToken::AccessToken(AccessToken::new(
+                user_token.access_token.clone(),
+            )),
+        };
+
+        client_settings
+            .into_client()
+            .set_revocation_url(revocation_url)
+            .revoke_token(token)?
+            .request_async(http_client)
+            .await
+            .context("Failed to revoke token")?;
+
+        Ok(())
+    }
+}
+
+/// The available oauth providers.
+#[derive(Clone)]
+pub struct ProviderRegistry {
+    providers: Vec<Arc<dyn OAuthProvider>>,
+    http_client: reqwest::Client,
+}
+
+impl ProviderRegistry {
+    /// Creates the registry with the builtin providers, the OpenID Connect provider is only
+    /// added if `OIDC_ISSUER_URL` is set and the mock provider if `MOCK_AUTH_ENABLED` is `true`.
+    ///
+    /// The providers are configured with the auth config file and environment variables.
+    pub fn new() -> Result<Self, anyhow::Error> {
+        let config = AuthConfig::load()?;
+        let http_client = provider_http_client()?;
+
+        let mut providers: Vec<Arc<dyn OAuthProvider>> = vec![
+            Arc::new(GoogleProvider::new(
+                config.provider(AuthProvider::Google)?,
+                http_client.clone(),
+            )),
+            Arc::new(GithubProvider::new(config.provider(AuthProvider::Github)?)),
+            Arc::new(DiscordProvider::new(
+                config.provider(AuthProvider::Discord)?,
+            )),
+        ];
+
+        if let Some(oidc) =
+            OidcProvider::from_env(config.provider(AuthProvider::Oidc)?, http_client.clone())
+        {
+            providers.push(Arc::new(oidc));
+        }
+
+        if let Some(mock) = MockProvider::from_env(config.provider(AuthProvider::Mock)?) {
+            providers.push(Arc::new(mock));
+        }
+
+        Ok(ProviderRegistry {
+            providers,
+            http_client,
+        })
+    }
+
+    /// Creates the registry with only the mock provider, used by the tests of the login flow.
+    #[cfg(test)]
+    pub fn mock() -> Result<Self, anyhow::Error> {
+        Ok(ProviderRegistry {
+            providers: vec![Arc::new(MockProvider::new(ProviderConfig::default()))],
+            http_client: provider_http_client()?,
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn OAuthProvider>> {
+        self.providers.iter()
+    }
+
+    pub fn get(&self, kind: AuthProvider) -> Option<&Arc<dyn OAuthProvider>> {
+        self.providers
+            .iter()
+            .find(|provider| provider.kind() == kind)
+    }
+
+    /// The client used for all the requests to the providers.
+    pub fn http_client(&self) -> &reqwest::Client {
+        &self.http_client
+    }
+}
+
+fn provider_http_client() -> Result<reqwest::Client, anyhow::Error> {
+    reqwest::ClientBuilder::new()
+        // Following redirects opens the client up to SSRF vulnerabilities.
+        .redirect(reqwest::redirect::Policy::none())
+        // A provider that doesn't respond must not hold the logins and logouts forever
+        .connect_timeout(PROVIDER_CONNECT_TIMEOUT)
+        .timeout(PROVIDER_TIMEOUT)
+        .build()
+        .context("Failed to create the provider http client")
+}
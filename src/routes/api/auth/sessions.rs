@@ -0,0 +1,166 @@
+use anyhow::Context;
+use axum::{
+    Extension, Json, Router,
+    extract::Path,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar};
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::{
+    constants::COOKIE_AUTH_SESSION,
+    misc::accepts_html,
+    models::UserSession,
+    server::{CurrentSession, SessionCache, UnauthorizedUser},
+};
+
+pub fn sessions_router() -> Router {
+    Router::new()
+        .route("/api/auth/sessions", get(list_sessions))
+        .route(
+            "/api/auth/sessions/revoke_others",
+            post(revoke_other_sessions),
+        )
+        .route(
+            "/api/auth/sessions/{session_id}/revoke",
+            post(revoke_session),
+        )
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ActiveSession {
+    #[serde(flatten)]
+    session: UserSession,
+    current: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct RevokedSessions {
+    revoked: usize,
+}
+
+/// An error of the sessions API, returned as `{"error": message}` with its status code.
+struct ApiError(StatusCode, &'static str);
+
+#[derive(serde::Serialize)]
+struct ApiErrorBody {
+    error: &'static str,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(ApiErrorBody { error: self.1 })).into_response()
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        tracing::error!("Something went wrong: {err:?}");
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong")
+    }
+}
+
+impl From<UnauthorizedUser> for ApiError {
+    fn from(_: UnauthorizedUser) -> Self {
+        ApiError(StatusCode::UNAUTHORIZED, "Not logged in")
+    }
+}
+
+/// Returns the active sessions of the current user, the session of this request is marked as
+/// `current`.
+async fn list_sessions(
+    current_session: Result<CurrentSession, UnauthorizedUser>,
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<impl IntoResponse, ApiError> {
+    let CurrentSession(current_session) = current_session?;
+
+    let sessions = crate::db::get_user_sessions(&pool, current_session.user_id)
+        .await
+        .context("Failed to get user sessions")?
+        .into_iter()
+        .map(|session| ActiveSession {
+            current: session.id == current_session.id,
+            session,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Json(sessions))
+}
+
+/// Signs out the given session of the current user, revoking the current session signs out this
+/// browser.
+///
+/// The forms of the sessions page are redirected back to it, the API clients get the number of
+/// revoked sessions.
+async fn revoke_session(
+    current_session: Result<CurrentSession, UnauthorizedUser>,
+    headers: HeaderMap,
+    cookies: CookieJar,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(session_cache): Extension<SessionCache>,
+    Path(session_id): Path<Uuid>,
+) -> Result<Response, ApiError> {
+    let CurrentSession(current_session) = current_session?;
+
+    let deleted = crate::db::delete_user_session_by_id(&pool, current_session.user_id, session_id)
+        .await
+        .context("Failed to delete user session")?;
+
+    session_cache.invalidate_user(current_session.user_id);
+
+    if !deleted {
+        return Err(ApiError(StatusCode::NOT_FOUND, "Session not found"));
+    }
+
+    let revoked = Json(RevokedSessions { revoked: 1 });
+
+    if session_id == current_session.id {
+        let mut remove_session_cookie = Cookie::new(COOKIE_AUTH_SESSION, "");
+        remove_session_cookie.set_path("/");
+        remove_session_cookie.make_removal();
+
+        let cookies = cookies.add(remove_session_cookie);
+        return Ok(if accepts_html(&headers) {
+            (cookies, Redirect::to("/login")).into_response()
+        } else {
+            (cookies, revoked).into_response()
+        });
+    }
+
+    Ok(if accepts_html(&headers) {
+        Redirect::to("/account/sessions").into_response()
+    } else {
+        revoked.into_response()
+    })
+}
+
+/// Signs out all the sessions of the current user except the session of this request.
+async fn revoke_other_sessions(
+    current_session: Result<CurrentSession, UnauthorizedUser>,
+    headers: HeaderMap,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(session_cache): Extension<SessionCache>,
+) -> Result<Response, ApiError> {
+    let CurrentSession(current_session) = current_session?;
+
+    let count =
+        crate::db::delete_other_user_sessions(&pool, current_session.user_id, current_session.id)
+            .await
+            .context("Failed to delete user sessions")?;
+
+    session_cache.invalidate_user(current_session.user_id);
+
+    tracing::debug!(
+        "revoked {count} sessions of user {}",
+        current_session.user_id
+    );
+
+    Ok(if accepts_html(&headers) {
+        Redirect::to("/account/sessions").into_response()
+    } else {
+        Json(RevokedSessions { revoked: count }).into_response()
+    })
+}
@@ -0,0 +1,153 @@
+use anyhow::Context;
+use chrono::NaiveDateTime;
+use oauth2::{AccessToken, RefreshToken, TokenResponse};
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use super::provider::{OAuthProvider, ProviderTokenResponse};
+use crate::misc::KeyedLocks;
+use crate::models::{AuthProvider, UserToken};
+
+/// Access tokens expiring within this margin are refreshed, so they don't expire while in use.
+const TOKEN_EXPIRATION_MARGIN: chrono::Duration = chrono::Duration::seconds(30);
+
+/// The provider tokens of the users, stored in the `user_token` table.
+#[derive(Clone)]
+pub struct ProviderTokens {
+    http_client: reqwest::Client,
+    refresh_locks: KeyedLocks<(Uuid, AuthProvider)>,
+}
+
+impl ProviderTokens {
+    pub fn new(http_client: reqwest::Client) -> Self {
+        ProviderTokens {
+            http_client,
+            refresh_locks: Default::default(),
+        }
+    }
+
+    /// Stores the access and refresh tokens of the given token response.
+    pub async fn save_token_response(
+        &self,
+        pool: &SqlitePool,
+        user_id: Uuid,
+        provider: AuthProvider,
+        token_response: &ProviderTokenResponse,
+    ) -> Result<(), anyhow::Error> {
+        crate::db::upsert_user_token(
+            pool,
+            user_id,
+            provider,
+            token_response.access_token().secret().to_owned(),
+            token_response
+                .refresh_token()
+                .map(|x| x.secret().to_owned()),
+            expires_at(token_response),
+        )
+        .await
+    }
+
+    /// Returns a valid access token of the user for the given provider, if the stored access
+    /// token has expired it is refreshed using the refresh token.
+    ///
+    /// Returns `None` if there is no token stored for the user.
+    pub async fn get_access_token(
+        &self,
+        pool: &SqlitePool,
+        provider: &dyn OAuthProvider,
+        user_id: Uuid,
+    ) -> Result<Option<AccessToken>, anyhow::Error> {
+        let Some(user_token) = crate::db::get_user_token(pool, user_id, provider.kind()).await?
+        else {
+            return Ok(None);
+        };
+
+        if !is_expired(&user_token) {
+            return Ok(Some(AccessToken::new(user_token.access_token)));
+        }
+
+        // Providers rotating the refresh tokens only accept each one once, so the concurrent
+        // requests of the same user must not refresh at the same time
+        let _guard = self.refresh_locks.lock((user_id, provider.kind())).await;
+
+        // The token may have been refreshed while we waited for the lock
+        let Some(user_token) = crate::db::get_user_token(pool, user_id, provider.kind()).await?
+        else {
+            return Ok(None);
+        };
+
+        if !is_expired(&user_token) {
+            return Ok(Some(AccessToken::new(user_token.access_token)));
+        }
+
+        let refresh_token = user_token.refresh_token.with_context(|| {
+            format!(
+                "The {} access token of user '{user_id}' expired and cannot be refreshed",
+                provider.kind()
+            )
+        })?;
+
+        let token_response = provider
+            .exchange_refresh_token(&self.http_client, &RefreshToken::new(refresh_token))
+            .await?;
+
+        self.save_token_response(pool, user_id, provider.kind(), &token_response)
+            .await?;
+
+        tracing::debug!(
+            "{} access token refreshed for user '{user_id}'",
+            provider.kind()
+        );
+
+        Ok(Some(token_response.access_token().clone()))
+    }
+
+    /// Revokes the stored tokens of the user in the provider and records the result.
+    ///
+    /// The tokens are removed even if the revocation failed, since they are no longer used after
+    /// logout or unlinking. The failed revocations are only recorded, they are not retried.
+    /// Returns whether the tokens were revoked.
+    pub async fn revoke_user_token(
+        &self,
+        pool: &SqlitePool,
+        provider: &dyn OAuthProvider,
+        user_id: Uuid,
+    ) -> Result<bool, anyhow::Error> {
+        let Some(user_token) = crate::db::get_user_token(pool, user_id, provider.kind()).await?
+        else {
+            return Ok(false);
+        };
+
+        let result = provider.revoke_token(&self.http_client, &user_token).await;
+        let error = result.as_ref().err().map(|err| format!("{err:#}"));
+        crate::db::create_token_revocation(pool, user_id, provider.kind(), error).await?;
+        crate::db::delete_user_token(pool, user_id, provider.kind()).await?;
+
+        match result {
+            Ok(()) => {
+                tracing::debug!("{} token revoked for user '{user_id}'", provider.kind());
+                Ok(true)
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "failed to revoke {} token of user '{user_id}': {err:#}",
+                    provider.kind()
+                );
+                Ok(false)
+            }
+        }
+    }
+}
+
+fn is_expired(user_token: &UserToken) -> bool {
+    let now = chrono::offset::Utc::now().naive_utc();
+
+    user_token
+        .expires_at
+        .is_some_and(|expires_at| now + TOKEN_EXPIRATION_MARGIN >= expires_at)
+}
+
+fn expires_at(token_response: &ProviderTokenResponse) -> Option<NaiveDateTime> {
+    let expires_in = chrono::Duration::from_std(token_response.expires_in()?).ok()?;
+    Some(chrono::offset::Utc::now().naive_utc() + expires_in)
+}
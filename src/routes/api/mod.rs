@@ -1,21 +1,30 @@
 mod auth;
 
+pub use auth::ProviderRegistry;
+pub use auth::ProviderTokens;
+
 use axum::response::IntoResponse;
 use axum::{
     Router,
     http::{HeaderMap, header},
+    middleware,
     response::Redirect,
     routing::post,
 };
 use axum_extra::extract::CookieJar;
 use cookie::Cookie;
 
-use crate::{constants::COOKIE_THEME, misc::Theme, server::UserTheme};
+use crate::{
+    constants::COOKIE_THEME,
+    misc::{Theme, redirect_target_or_root},
+    server::{UserTheme, verify_csrf},
+};
 
-pub fn api_router() -> Router {
+pub fn api_router(providers: &ProviderRegistry) -> Router {
     Router::new()
-        .merge(auth::auth_router())
         .route("/api/toggle_theme", post(toggle_theme))
+        .route_layer(middleware::from_fn(verify_csrf))
+        .merge(auth::auth_router(providers))
 }
 
 async fn toggle_theme(UserTheme(theme): UserTheme, headers: HeaderMap) -> impl IntoResponse {
@@ -32,11 +41,9 @@ async fn toggle_theme(UserTheme(theme): UserTheme, headers: HeaderMap) -> impl I
         .into();
 
     let cookies = CookieJar::new().add(theme_cookie);
-    let referer = headers.get(header::REFERER);
-    let path = match referer {
-        Some(referer) => referer.to_str().expect("Failed to get referer path"),
-        None => "/",
-    };
+    // Back to the page of the theme button, if it's on this site
+    let referer = headers.get(header::REFERER).and_then(|x| x.to_str().ok());
+    let path = redirect_target_or_root(referer);
 
-    (cookies, Redirect::to(path))
+    (cookies, Redirect::to(&path))
 }
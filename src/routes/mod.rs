@@ -1,6 +1,10 @@
 mod api;
 mod pages;
 
+pub use api::ProviderRegistry;
+pub use api::ProviderTokens;
 pub use api::api_router;
+pub use pages::AvatarCache;
+pub use pages::ImageProxy;
 pub use pages::pages_router;
 pub use pages::error_handler_middleware;
\ No newline at end of file
@@ -0,0 +1,556 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::Context;
+use axum::{
+    Extension,
+    extract::{Path, Query},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use image::{ImageFormat, ImageReader, Limits, imageops::FilterType};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use unicode_segmentation::UnicodeSegmentation;
+use uuid::Uuid;
+
+use super::image_proxy::{FetchImageError, ImageProxy};
+use crate::constants::{
+    AVATAR_CACHE_CONTROL, AVATAR_FAILURE_TTL, AVATAR_MAX_SOURCE_SIZE, AVATAR_SIZES,
+    DEFAULT_AVATAR_CACHE_DIR, INITIALS_AVATAR_CSP,
+};
+use crate::misc::{KeyedLocks, lock_unpoisoned};
+use crate::models::User;
+
+type Failures = HashMap<(Uuid, String), (FetchImageError, Instant)>;
+
+/// Disk cache of the user images, resized to the sizes shown in the pages.
+///
+/// The images are downloaded through the `ImageProxy` the first time they are requested and
+/// stored in the `AVATAR_CACHE_DIR` directory. The files are named after the user and a hash of
+/// the image url, so a new image is downloaded when the user changes it.
+///
+/// The images of a user are downloaded by one request at a time, and the failed downloads are
+/// remembered for `AVATAR_FAILURE_TTL` so a broken image is not downloaded on each page view.
+#[derive(Clone)]
+pub struct AvatarCache {
+    dir: PathBuf,
+    image_proxy: ImageProxy,
+    user_locks: KeyedLocks<Uuid>,
+    failures: Arc<Mutex<Failures>>,
+}
+
+impl AvatarCache {
+    pub fn from_env(image_proxy: ImageProxy) -> Result<Self, anyhow::Error> {
+        let dir = match std::env::var("AVATAR_CACHE_DIR") {
+            Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(DEFAULT_AVATAR_CACHE_DIR),
+        };
+
+        AvatarCache::new(dir, image_proxy)
+    }
+
+    /// Creates the cache in the given directory, creating the directory if it doesn't exist.
+    pub fn new(dir: PathBuf, image_proxy: ImageProxy) -> Result<Self, anyhow::Error> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create avatar cache dir '{}'", dir.display()))?;
+
+        Ok(AvatarCache {
+            dir,
+            image_proxy,
+            user_locks: Default::default(),
+            failures: Default::default(),
+        })
+    }
+
+    /// Returns the PNG image of the given size, downloading and resizing the image if not cached.
+    async fn get(
+        &self,
+        user_id: Uuid,
+        image_url: &str,
+        size: u32,
+    ) -> Result<Vec<u8>, FetchImageError> {
+        let key = short_hash(image_url);
+        if let Some(result) = self.cached(user_id, &key, size).await {
+            return result;
+        }
+
+        let _guard = self.user_locks.lock(user_id).await;
+
+        // The image may have been downloaded while we waited for the lock
+        if let Some(result) = self.cached(user_id, &key, size).await {
+            return result;
+        }
+
+        let resized = match self.fetch(user_id, image_url).await {
+            Ok(resized) => resized,
+            Err(err) => {
+                self.add_failure(user_id, &key, err);
+                return Err(err);
+            }
+        };
+
+        if let Err(err) = self.store(user_id, &key, &resized).await {
+            tracing::error!("failed to store avatar of user '{user_id}': {err:#}");
+        }
+
+        resized
+            .into_iter()
+            .find(|(x, _)| *x == size)
+            .map(|(_, bytes)| bytes)
+            .ok_or(FetchImageError::Failed)
+    }
+
+    /// Returns the stored image, or the error of a recent failed download of the same image.
+    async fn cached(
+        &self,
+        user_id: Uuid,
+        key: &str,
+        size: u32,
+    ) -> Option<Result<Vec<u8>, FetchImageError>> {
+        if let Ok(bytes) = tokio::fs::read(self.path(user_id, key, size)).await {
+            return Some(Ok(bytes));
+        }
+
+        self.recent_failure(user_id, key).map(Err)
+    }
+
+    /// Downloads the image and resizes it to each of the `AVATAR_SIZES`.
+    async fn fetch(
+        &self,
+        user_id: Uuid,
+        image_url: &str,
+    ) -> Result<Vec<(u32, Vec<u8>)>, FetchImageError> {
+        let image = self.image_proxy.fetch(image_url).await?;
+
+        // Decoding and resizing are CPU bound
+        tokio::task::spawn_blocking(move || resize_image(&image.bytes))
+            .await
+            .map_err(|_| FetchImageError::Failed)?
+            .map_err(|err| {
+                tracing::warn!("failed to resize avatar of user '{user_id}': {err:#}");
+                FetchImageError::NotImage
+            })
+    }
+
+    /// Writes the resized images and removes the previous images of the user.
+    ///
+    /// Must be called holding the lock of the user, so no other request is writing its images.
+    async fn store(
+        &self,
+        user_id: Uuid,
+        key: &str,
+        resized: &[(u32, Vec<u8>)],
+    ) -> Result<(), anyhow::Error> {
+        for (size, bytes) in resized {
+            // Written to a temporary file first, so a concurrent request never reads half a file
+            let path = self.path(user_id, key, *size);
+            let tmp_path = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+            tokio::fs::write(&tmp_path, bytes).await?;
+            tokio::fs::rename(&tmp_path, &path).await?;
+        }
+
+        self.remove_files(user_id, Some(key)).await
+    }
+
+    /// Removes the cached images of the user.
+    pub async fn remove(&self, user_id: Uuid) -> Result<(), anyhow::Error> {
+        let _guard = self.user_locks.lock(user_id).await;
+
+        lock_unpoisoned(&self.failures).retain(|(x, _), _| *x != user_id);
+
+        self.remove_files(user_id, None).await
+    }
+
+    /// Removes the files of the user, except the images of `keep_key`.
+    async fn remove_files(
+        &self,
+        user_id: Uuid,
+        keep_key: Option<&str>,
+    ) -> Result<(), anyhow::Error> {
+        let prefix = format!("{user_id}-");
+        let keep_prefix = keep_key.map(|key| format!("{user_id}-{key}-"));
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+
+            let keep = keep_prefix
+                .as_ref()
+                .is_some_and(|keep_prefix| file_name.starts_with(keep_prefix));
+
+            if file_name.starts_with(&prefix) && !keep {
+                tokio::fs::remove_file(entry.path()).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn recent_failure(&self, user_id: Uuid, key: &str) -> Option<FetchImageError> {
+        let failures = lock_unpoisoned(&self.failures);
+
+        failures
+            .get(&(user_id, key.to_owned()))
+            .filter(|(_, failed_at)| failed_at.elapsed() < AVATAR_FAILURE_TTL)
+            .map(|(err, _)| *err)
+    }
+
+    fn add_failure(&self, user_id: Uuid, key: &str, err: FetchImageError) {
+        let mut failures = lock_unpoisoned(&self.failures);
+
+        // Removes the expired failures, so the map only holds the recent ones
+        failures.retain(|_, (_, failed_at)| failed_at.elapsed() < AVATAR_FAILURE_TTL);
+        failures.insert((user_id, key.to_owned()), (err, Instant::now()));
+    }
+
+    fn path(&self, user_id: Uuid, key: &str, size: u32) -> PathBuf {
+        self.dir.join(format!("{user_id}-{key}-{size}.png"))
+    }
+}
+
+/// Returns a short hash of the value, it identifies the images in the file names and etags.
+fn short_hash(value: &str) -> String {
+    let hash = Sha256::digest(value.as_bytes());
+    crate::misc::hex(&hash[..8])
+}
+
+/// Resizes the image to each of the `AVATAR_SIZES`, cropping it to a square, encoded as PNG.
+fn resize_image(bytes: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, anyhow::Error> {
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+
+    // Small files can still decode to huge images
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(AVATAR_MAX_SOURCE_SIZE);
+    limits.max_image_height = Some(AVATAR_MAX_SOURCE_SIZE);
+    reader.limits(limits);
+
+    let image = reader.decode()?;
+
+    AVATAR_SIZES
+        .iter()
+        .map(|&size| {
+            let mut bytes = Vec::new();
+            image
+                .resize_to_fill(size, size, FilterType::Lanczos3)
+                .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+
+            Ok((size, bytes))
+        })
+        .collect()
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct AvatarQuery {
+    size: Option<u32>,
+}
+
+/// Returns the image of the user in one of the `AVATAR_SIZES`, the largest by default.
+pub async fn avatar(
+    Extension(avatar_cache): Extension<AvatarCache>,
+    Extension(pool): Extension<SqlitePool>,
+    Path(user_id): Path<Uuid>,
+    Query(query): Query<AvatarQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (size, user) = match avatar_size_and_user(&pool, user_id, query).await {
+        Ok(x) => x,
+        Err(resp) => return resp,
+    };
+
+    let Some(image_url) = user.image_url else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let etag = format!("\"{}-{size}\"", short_hash(&image_url));
+    let Ok(etag) = HeaderValue::from_str(&etag) else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(AVATAR_CACHE_CONTROL),
+        ),
+    ];
+
+    if headers.get(header::IF_NONE_MATCH) == Some(&etag) {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+
+    match avatar_cache.get(user_id, &image_url, size).await {
+        Ok(bytes) => (
+            cache_headers,
+            [(header::CONTENT_TYPE, HeaderValue::from_static("image/png"))],
+            bytes,
+        )
+            .into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Returns an SVG image with the initials of the user, for the users without image.
+///
+/// The image is generated here so the usernames are not sent to other sites.
+pub async fn initials_avatar(
+    Extension(pool): Extension<SqlitePool>,
+    Path(user_id): Path<Uuid>,
+    Query(query): Query<AvatarQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (size, user) = match avatar_size_and_user(&pool, user_id, query).await {
+        Ok(x) => x,
+        Err(resp) => return resp,
+    };
+
+    let svg = initials_svg(&initials(&user.username), &avatar_color(user_id), size);
+
+    let etag = format!("\"{}\"", short_hash(&svg));
+    let Ok(etag) = HeaderValue::from_str(&etag) else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(AVATAR_CACHE_CONTROL),
+        ),
+    ];
+
+    if headers.get(header::IF_NONE_MATCH) == Some(&etag) {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+
+    (
+        cache_headers,
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("image/svg+xml"),
+            ),
+            // The image is opened directly only by mistake, but it must not run anything then
+            (
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_static(INITIALS_AVATAR_CSP),
+            ),
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ),
+        ],
+        svg,
+    )
+        .into_response()
+}
+
+async fn avatar_size_and_user(
+    pool: &SqlitePool,
+    user_id: Uuid,
+    query: AvatarQuery,
+) -> Result<(u32, User), Response> {
+    let size = query.size.unwrap_or(AVATAR_SIZES[AVATAR_SIZES.len() - 1]);
+
+    if !AVATAR_SIZES.contains(&size) {
+        return Err((StatusCode::BAD_REQUEST, "Invalid avatar size").into_response());
+    }
+
+    match crate::db::get_user_by_id(pool, user_id).await {
+        Ok(Some(user)) => Ok((size, user)),
+        Ok(None) => Err(StatusCode::NOT_FOUND.into_response()),
+        Err(err) => {
+            tracing::error!("failed to get user: {err:#}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+/// Returns the first letter of the first two words of the username, or the first two letters if
+/// it's a single word.
+fn initials(username: &str) -> String {
+    let mut words = username.split_whitespace();
+
+    let initials: String = match (words.next(), words.next()) {
+        (Some(first), Some(second)) => first
+            .graphemes(true)
+            .take(1)
+            .chain(second.graphemes(true).take(1))
+            .collect(),
+        (Some(first), None) => first.graphemes(true).take(2).collect(),
+        _ => "?".to_owned(),
+    };
+
+    initials.to_uppercase()
+}
+
+/// Returns a color picked from the user id, so each user keeps the same color.
+fn avatar_color(user_id: Uuid) -> String {
+    let hash = Sha256::digest(user_id.as_bytes());
+    let hue = u16::from_be_bytes([hash[0], hash[1]]) % 360;
+
+    // The saturation and lightness are fixed so the white text is always readable
+    format!("hsl({hue}, 55%, 40%)")
+}
+
+fn initials_svg(initials: &str, color: &str, size: u32) -> String {
+    let font_size = size * 2 / 5;
+    let initials = escape_xml(initials);
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{size}" height="{size}" viewBox="0 0 {size} {size}">
+  <rect width="100%" height="100%" fill="{color}"/>
+  <text x="50%" y="50%" dominant-baseline="central" text-anchor="middle" fill="#ffffff" font-family="system-ui, sans-serif" font-size="{font_size}">{initials}</text>
+</svg>"##
+    )
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn avatar_cache() -> AvatarCache {
+        let dir = std::env::temp_dir().join(format!("avatars-{}", Uuid::new_v4()));
+        AvatarCache::new(dir, ImageProxy::from_env().unwrap()).unwrap()
+    }
+
+    fn file_names(avatar_cache: &AvatarCache) -> Vec<String> {
+        let mut file_names: Vec<String> = std::fs::read_dir(&avatar_cache.dir)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+
+        file_names.sort();
+        file_names
+    }
+
+    #[tokio::test]
+    async fn storing_an_image_removes_only_the_previous_images_of_the_user() {
+        let avatar_cache = avatar_cache();
+        let user_id = Uuid::new_v4();
+        let other_user_id = Uuid::new_v4();
+        let resized = [(40, vec![1]), (80, vec![2])];
+
+        avatar_cache.store(user_id, "old", &resized).await.unwrap();
+        avatar_cache
+            .store(other_user_id, "old", &resized)
+            .await
+            .unwrap();
+
+        // A file of the new image being written by another request
+        let tmp_path = avatar_cache
+            .path(user_id, "new", 80)
+            .with_extension("x.tmp");
+        std::fs::write(&tmp_path, [3]).unwrap();
+
+        avatar_cache
+            .store(user_id, "new", &resized[..1])
+            .await
+            .unwrap();
+
+        let mut expected = vec![
+            format!("{other_user_id}-old-40.png"),
+            format!("{other_user_id}-old-80.png"),
+            format!("{user_id}-new-40.png"),
+            format!("{user_id}-new-80.x.tmp"),
+        ];
+        expected.sort();
+        assert_eq!(file_names(&avatar_cache), expected);
+
+        avatar_cache.remove(user_id).await.unwrap();
+
+        let mut expected = vec![
+            format!("{other_user_id}-old-40.png"),
+            format!("{other_user_id}-old-80.png"),
+        ];
+        expected.sort();
+        assert_eq!(file_names(&avatar_cache), expected);
+
+        std::fs::remove_dir_all(&avatar_cache.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_downloads_are_remembered_per_image() {
+        let avatar_cache = avatar_cache();
+        let user_id = Uuid::new_v4();
+        let image_url = "http://example.com/avatar.png";
+        let key = short_hash(image_url);
+
+        assert!(avatar_cache.recent_failure(user_id, &key).is_none());
+
+        let result = avatar_cache.get(user_id, image_url, 40).await;
+        assert!(matches!(result, Err(FetchImageError::NotAllowed)));
+        assert!(matches!(
+            avatar_cache.recent_failure(user_id, &key),
+            Some(FetchImageError::NotAllowed)
+        ));
+
+        // Another image of the user, or the same image of another user, is still downloaded
+        let other_key = short_hash("https://example.com/avatar.png");
+        assert!(avatar_cache.recent_failure(user_id, &other_key).is_none());
+        assert!(avatar_cache.recent_failure(Uuid::new_v4(), &key).is_none());
+
+        std::fs::remove_dir_all(&avatar_cache.dir).unwrap();
+    }
+
+    #[test]
+    fn initials_of_one_word_are_its_first_two_letters() {
+        assert_eq!(initials("alice"), "AL");
+        assert_eq!(initials("a"), "A");
+        assert_eq!(initials("  a  "), "A");
+    }
+
+    #[test]
+    fn initials_of_several_words_are_the_first_letters_of_the_first_two() {
+        assert_eq!(initials("ada lovelace"), "AL");
+        assert_eq!(initials("ada  king\tlovelace"), "AK");
+        assert_eq!(initials("a b"), "AB");
+    }
+
+    #[test]
+    fn initials_keep_multibyte_letters_whole() {
+        assert_eq!(initials("émile"), "ÉM");
+        assert_eq!(initials("élodie ñúñez"), "ÉÑ");
+        assert_eq!(initials("日本語"), "日本");
+        assert_eq!(initials("é"), "É");
+        // Combining marks and emoji sequences are a single letter
+        assert_eq!(initials("e\u{301}mile"), "E\u{301}M");
+        assert_eq!(initials("👩‍💻 dev"), "👩‍💻D");
+    }
+
+    #[test]
+    fn initials_of_blank_usernames_are_a_question_mark() {
+        assert_eq!(initials(""), "?");
+        assert_eq!(initials(" "), "?");
+        assert_eq!(initials("\t\n "), "?");
+        assert_eq!(initials("\u{3000}\u{a0}"), "?");
+    }
+
+    #[test]
+    fn initials_are_escaped_in_the_svg() {
+        let svg = initials_svg(&initials("<b>"), "hsl(0, 55%, 40%)", 40);
+        assert!(svg.contains(">&lt;B<"));
+        assert!(!svg.contains("<b>") && !svg.contains("<B>"));
+    }
+}
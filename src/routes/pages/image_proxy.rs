@@ -0,0 +1,354 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::{
+    Extension,
+    extract::Query,
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use oauth2::url::{Host, Url};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+use crate::constants::{
+    DEFAULT_IMAGE_PROXY_HOSTS, IMAGE_PROXY_CONNECT_TIMEOUT, IMAGE_PROXY_MAX_SIZE,
+    IMAGE_PROXY_TIMEOUT,
+};
+
+/// Fetches the user images of the providers, so the browsers don't request them directly.
+///
+/// Only the https urls of the `IMAGE_PROXY_HOSTS` hosts (comma separated) are fetched, and only if
+/// they resolve to public addresses, so the proxy cannot be used to reach the internal network.
+#[derive(Clone)]
+pub struct ImageProxy {
+    allowed_hosts: Arc<Vec<String>>,
+    client: reqwest::Client,
+}
+
+impl ImageProxy {
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let allowed_hosts = match std::env::var("IMAGE_PROXY_HOSTS") {
+            Ok(hosts) if !hosts.is_empty() => hosts
+                .split(',')
+                .map(|x| x.trim().to_lowercase())
+                .filter(|x| !x.is_empty())
+                .collect(),
+            _ => DEFAULT_IMAGE_PROXY_HOSTS
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+        };
+
+        let client = reqwest::ClientBuilder::new()
+            // Following redirects would allow to leave the allowed hosts
+            .redirect(reqwest::redirect::Policy::none())
+            // A proxy would resolve the hosts itself
+            .no_proxy()
+            .dns_resolver(Arc::new(PublicResolver))
+            .connect_timeout(IMAGE_PROXY_CONNECT_TIMEOUT)
+            .timeout(IMAGE_PROXY_TIMEOUT)
+            .build()
+            .context("Failed to create the image proxy client")?;
+
+        Ok(ImageProxy {
+            allowed_hosts: Arc::new(allowed_hosts),
+            client,
+        })
+    }
+
+    /// Returns the url if it's an https url of an allowed host.
+    fn allowed_url(&self, url: &str) -> Option<Url> {
+        let url = Url::parse(url).ok()?;
+
+        let is_allowed = url.scheme() == "https"
+            && url.username().is_empty()
+            && url.password().is_none()
+            && url.port().is_none()
+            && match url.host()? {
+                Host::Domain(domain) => self.allowed_hosts.iter().any(|x| x == domain),
+                // The resolver is not used for ip addresses
+                Host::Ipv4(_) | Host::Ipv6(_) => false,
+            };
+
+        is_allowed.then_some(url)
+    }
+
+    /// Fetches the image of the given url, if it's allowed.
+    pub async fn fetch(&self, url: &str) -> Result<FetchedImage, FetchImageError> {
+        let url = self.allowed_url(url).ok_or(FetchImageError::NotAllowed)?;
+
+        let mut resp = match self.client.get(url.clone()).send().await {
+            Ok(resp) if resp.status().is_success() => resp,
+            Ok(resp) => {
+                tracing::warn!("failed to fetch image '{url}': {}", resp.status());
+                return Err(FetchImageError::Failed);
+            }
+            Err(err) => {
+                tracing::warn!("failed to fetch image '{url}': {err}");
+                return Err(FetchImageError::Failed);
+            }
+        };
+
+        // SVG images can run scripts, so they are not served from our origin
+        let content_type = resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .filter(|x| x.starts_with("image/") && !x.starts_with("image/svg"))
+            .and_then(|x| HeaderValue::from_str(x).ok())
+            .ok_or(FetchImageError::NotImage)?;
+
+        if resp
+            .content_length()
+            .is_some_and(|x| x > IMAGE_PROXY_MAX_SIZE as u64)
+        {
+            return Err(FetchImageError::TooLarge);
+        }
+
+        // The content length can be missing or wrong, so the size is also checked while reading
+        let mut bytes = Vec::new();
+        loop {
+            match resp.chunk().await {
+                Ok(Some(chunk)) if bytes.len() + chunk.len() <= IMAGE_PROXY_MAX_SIZE => {
+                    bytes.extend_from_slice(&chunk);
+                }
+                Ok(Some(_)) => return Err(FetchImageError::TooLarge),
+                Ok(None) => break,
+                Err(err) => {
+                    tracing::warn!("failed to read image '{url}': {err}");
+                    return Err(FetchImageError::Failed);
+                }
+            }
+        }
+
+        Ok(FetchedImage {
+            content_type,
+            bytes,
+        })
+    }
+}
+
+/// An image fetched by the proxy.
+pub struct FetchedImage {
+    pub content_type: HeaderValue,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum FetchImageError {
+    NotAllowed,
+    Failed,
+    NotImage,
+    TooLarge,
+}
+
+impl IntoResponse for FetchImageError {
+    fn into_response(self) -> Response {
+        match self {
+            FetchImageError::NotAllowed => {
+                (StatusCode::BAD_REQUEST, "The image url is not allowed").into_response()
+            }
+            FetchImageError::Failed => {
+                (StatusCode::BAD_GATEWAY, "Failed to fetch image").into_response()
+            }
+            FetchImageError::NotImage => {
+                (StatusCode::BAD_GATEWAY, "The url is not an image").into_response()
+            }
+            FetchImageError::TooLarge => {
+                (StatusCode::BAD_GATEWAY, "The image is too large").into_response()
+            }
+        }
+    }
+}
+
+/// Resolves the hosts with the system resolver, rejecting the private and local addresses.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_owned();
+
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(addr.ip()))
+                .collect::<Vec<SocketAddr>>();
+
+            if addrs.is_empty() {
+                tracing::warn!("image proxy host '{host}' has no public address");
+                return Err(format!("'{host}' has no public address").into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ipv4(ip),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8 "this network"
+        || a == 0
+        // 100.64.0.0/10 shared address space
+        || (a == 100 && (64..128).contains(&b))
+        // 198.18.0.0/15 benchmarking
+        || (a == 198 && (18..20).contains(&b))
+        // 240.0.0.0/4 reserved
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let [a, b, c, d, e, f, ..] = ip.segments();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // ::/96 ipv4 compatible, deprecated but still routed to the ipv4 address by some stacks
+        || [a, b, c, d, e, f] == [0; 6]
+        // fc00::/7 unique local
+        || (a & 0xfe00) == 0xfc00
+        // fe80::/10 link local
+        || (a & 0xffc0) == 0xfe80
+        // 2001:db8::/32 documentation
+        || (a == 0x2001 && b == 0x0db8)
+        // 2001::/32 Teredo and 2002::/16 6to4 tunnel to any ipv4 address
+        || (a == 0x2001 && b == 0)
+        || a == 0x2002
+        // 64:ff9b::/96 and 64:ff9b:1::/48 could reach private ipv4 addresses through NAT64
+        || (a == 0x64 && b == 0xff9b))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ImageQuery {
+    url: Option<String>,
+}
+
+pub async fn proxy_google_image(
+    Extension(image_proxy): Extension<ImageProxy>,
+    Query(query): Query<ImageQuery>,
+) -> Response {
+    let Some(url) = query.url else {
+        return (StatusCode::BAD_REQUEST, "Missing `url` param").into_response();
+    };
+
+    let image = match image_proxy.fetch(&url).await {
+        Ok(image) => image,
+        Err(err) => return err.into_response(),
+    };
+
+    (
+        [
+            (header::CONTENT_TYPE, image.content_type),
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ),
+        ],
+        image.bytes,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_public(ip: &str) -> bool {
+        is_public_ip(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn public_addresses_are_allowed() {
+        assert!(is_public("8.8.8.8"));
+        assert!(is_public("142.250.0.1"));
+        assert!(is_public("2607:f8b0:4004:800::2004"));
+        assert!(is_public("::ffff:8.8.8.8"));
+    }
+
+    #[test]
+    fn private_ipv4_addresses_are_rejected() {
+        for ip in [
+            "0.0.0.0",
+            "0.1.2.3",
+            "10.0.0.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "172.16.0.1",
+            "172.31.255.255",
+            "192.168.1.1",
+            "192.0.2.1",
+            "198.18.0.1",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!is_public(ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn shared_address_space_is_rejected() {
+        assert!(!is_public("100.64.0.1"));
+        assert!(!is_public("100.127.255.255"));
+        assert!(is_public("100.63.255.255"));
+        assert!(is_public("100.128.0.0"));
+    }
+
+    #[test]
+    fn private_ipv6_addresses_are_rejected() {
+        for ip in [
+            "::",
+            "::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "ff02::1",
+            "2001:db8::1",
+        ] {
+            assert!(!is_public(ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn ipv6_addresses_embedding_ipv4_addresses_are_rejected() {
+        for ip in [
+            // ipv4 mapped
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "::ffff:169.254.169.254",
+            // ipv4 compatible
+            "::7f00:1",
+            "::127.0.0.1",
+            "::8.8.8.8",
+            // NAT64
+            "64:ff9b::7f00:1",
+            "64:ff9b::8.8.8.8",
+            "64:ff9b:1::a00:1",
+            // 6to4
+            "2002:7f00:1::",
+            "2002:a00:1::1",
+            // Teredo
+            "2001:0:4136:e378:8000:63bf:3fff:fdd2",
+        ] {
+            assert!(!is_public(ip), "{ip}");
+        }
+    }
+}
@@ -1,24 +1,37 @@
 use crate::{
-    misc::{PageError, Theme},
+    misc::{PageError, Theme, accepts_html, safe_redirect_target},
     models::User,
-    server::{CurrentUser, UserTheme},
+    routes::ProviderRegistry,
+    server::{CsrfToken, CurrentSession, CurrentUser, UserTheme},
 };
 use askama::Template;
 use axum::{
-    Router,
-    extract::Request,
-    http::StatusCode,
+    Extension, Router,
+    extract::{Query, Request},
+    http::{Method, StatusCode},
     middleware::{self, Next},
     response::Redirect,
     routing::get,
 };
 use axum::response::{Html, IntoResponse};
+use oauth2::url::form_urlencoded;
+use sqlx::SqlitePool;
+
+mod avatars;
+mod image_proxy;
+
+pub use avatars::AvatarCache;
+pub use image_proxy::ImageProxy;
 
 pub fn pages_router() -> Router {
     Router::new()
         .route("/", get(home))
         .route("/login", get(login))
-        .route("/proxy/google_image", get(proxy_google_image))
+        .route("/account", get(account))
+        .route("/account/sessions", get(account_sessions))
+        .route("/proxy/google_image", get(image_proxy::proxy_google_image))
+        .route("/avatars/{user_id}", get(avatars::avatar))
+        .route("/avatars/{user_id}/initials", get(avatars::initials_avatar))
         .layer(middleware::from_fn(auth_middleware))
         .fallback(not_found)
 }
@@ -28,13 +41,19 @@ pub fn pages_router() -> Router {
 struct HomeTemplate {
     theme: Theme,
     user: Option<User>,
+    csrf_token: String,
 }
 
-async fn home(CurrentUser(user): CurrentUser, UserTheme(theme): UserTheme) -> HomeTemplate {
+async fn home(
+    CurrentUser(user): CurrentUser,
+    UserTheme(theme): UserTheme,
+    CsrfToken(csrf_token): CsrfToken,
+) -> HomeTemplate {
     let theme = theme.unwrap_or_default();
     HomeTemplate {
         theme,
         user: Some(user),
+        csrf_token,
     }
 }
 
@@ -51,16 +70,63 @@ impl IntoResponse for HomeTemplate {
     }
 }
 
+struct LoginProvider {
+    id: String,
+    name: String,
+    logo_url: Option<String>,
+}
+
 #[derive(Template)]
 #[template(path = "login.html")]
 struct LoginTemplate {
     theme: Theme,
     user: Option<User>,
+    csrf_token: String,
+    providers: Vec<LoginProvider>,
+    return_to: Option<String>,
+}
+
+impl LoginTemplate {
+    fn new(
+        theme: Theme,
+        csrf_token: String,
+        providers: &ProviderRegistry,
+        return_to: Option<String>,
+    ) -> Self {
+        let providers = providers
+            .iter()
+            .map(|provider| LoginProvider {
+                id: provider.kind().to_string(),
+                name: provider.display_name(),
+                logo_url: provider.logo_url().map(|x| x.to_owned()),
+            })
+            .collect();
+
+        LoginTemplate {
+            theme,
+            user: None,
+            csrf_token,
+            providers,
+            return_to,
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct LoginQuery {
+    return_to: Option<String>,
 }
 
-async fn login(UserTheme(theme): UserTheme) -> LoginTemplate {
+async fn login(
+    UserTheme(theme): UserTheme,
+    CsrfToken(csrf_token): CsrfToken,
+    Extension(providers): Extension<ProviderRegistry>,
+    Query(query): Query<LoginQuery>,
+) -> LoginTemplate {
     let theme = theme.unwrap_or_default();
-    LoginTemplate { theme, user: None }
+    let return_to = query.return_to.as_deref().and_then(safe_redirect_target);
+
+    LoginTemplate::new(theme, csrf_token, &providers, return_to)
 }
 
 impl IntoResponse for LoginTemplate {
@@ -76,20 +142,285 @@ impl IntoResponse for LoginTemplate {
     }
 }
 
+struct AccountProvider {
+    id: String,
+    name: String,
+    logo_url: Option<String>,
+    account_id: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "account.html")]
+struct AccountTemplate {
+    theme: Theme,
+    user: Option<User>,
+    csrf_token: String,
+    providers: Vec<AccountProvider>,
+    can_unlink: bool,
+}
+
+async fn account(
+    CurrentUser(user): CurrentUser,
+    UserTheme(theme): UserTheme,
+    CsrfToken(csrf_token): CsrfToken,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(providers): Extension<ProviderRegistry>,
+) -> Result<AccountTemplate, StatusCode> {
+    let theme = theme.unwrap_or_default();
+    let identities = crate::db::get_user_identities(&pool, user.id)
+        .await
+        .map_err(|err| {
+            tracing::error!("failed to get user identities: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let providers = providers
+        .iter()
+        .map(|provider| AccountProvider {
+            id: provider.kind().to_string(),
+            name: provider.display_name(),
+            logo_url: provider.logo_url().map(|x| x.to_owned()),
+            account_id: identities
+                .iter()
+                .find(|x| x.provider == provider.kind())
+                .map(|x| x.account_id.clone()),
+        })
+        .collect();
+
+    Ok(AccountTemplate {
+        theme,
+        user: Some(user),
+        csrf_token,
+        providers,
+        can_unlink: identities.len() > 1,
+    })
+}
+
+impl IntoResponse for AccountTemplate {
+    fn into_response(self) -> axum::response::Response {
+        match self.render() {
+            Ok(html) => Html(html).into_response(),
+            Err(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to render template: {err}"),
+            )
+                .into_response(),
+        }
+    }
+}
+
+struct AccountSession {
+    id: String,
+    device: String,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+    last_seen_at: String,
+    created_at: String,
+    remember_me: bool,
+    current: bool,
+}
+
+#[derive(Template)]
+#[template(path = "account_sessions.html")]
+struct AccountSessionsTemplate {
+    theme: Theme,
+    user: Option<User>,
+    csrf_token: String,
+    sessions: Vec<AccountSession>,
+}
+
+async fn account_sessions(
+    CurrentUser(user): CurrentUser,
+    CurrentSession(current_session): CurrentSession,
+    UserTheme(theme): UserTheme,
+    CsrfToken(csrf_token): CsrfToken,
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<AccountSessionsTemplate, StatusCode> {
+    let theme = theme.unwrap_or_default();
+    let sessions = crate::db::get_user_sessions(&pool, user.id)
+        .await
+        .map_err(|err| {
+            tracing::error!("failed to get user sessions: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let date_format = "%Y-%m-%d %H:%M UTC";
+    let sessions = sessions
+        .into_iter()
+        .map(|session| AccountSession {
+            id: session.id.to_string(),
+            device: describe_user_agent(session.user_agent.as_deref()),
+            last_seen_at: session
+                .last_seen_at
+                .unwrap_or(session.created_at)
+                .format(date_format)
+                .to_string(),
+            created_at: session.created_at.format(date_format).to_string(),
+            user_agent: session.user_agent,
+            ip_address: session.ip_address,
+            remember_me: session.remember_me,
+            current: session.id == current_session.id,
+        })
+        .collect();
+
+    Ok(AccountSessionsTemplate {
+        theme,
+        user: Some(user),
+        csrf_token,
+        sessions,
+    })
+}
+
+/// Returns a short description of the browser and OS of the user agent, like "Firefox on Linux".
+fn describe_user_agent(user_agent: Option<&str>) -> String {
+    let Some(user_agent) = user_agent else {
+        return "Unknown device".to_owned();
+    };
+
+    // The order matters, most browsers also include the tokens of the browsers they are based on
+    let browsers = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+        ("curl/", "curl"),
+    ];
+
+    let systems = [
+        ("Android", "Android"),
+        ("iPhone", "iOS"),
+        ("iPad", "iPadOS"),
+        ("Windows", "Windows"),
+        ("Mac OS X", "macOS"),
+        ("CrOS", "ChromeOS"),
+        ("Linux", "Linux"),
+    ];
+
+    let find = |names: &[(&str, &'static str)]| {
+        names
+            .iter()
+            .find(|(token, _)| user_agent.contains(token))
+            .map(|(_, name)| *name)
+    };
+
+    match (find(&browsers), find(&systems)) {
+        (Some(browser), Some(system)) => format!("{browser} on {system}"),
+        (Some(browser), None) => browser.to_owned(),
+        (None, Some(system)) => system.to_owned(),
+        (None, None) => "Unknown device".to_owned(),
+    }
+}
+
+impl IntoResponse for AccountSessionsTemplate {
+    fn into_response(self) -> axum::response::Response {
+        match self.render() {
+            Ok(html) => Html(html).into_response(),
+            Err(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to render template: {err}"),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// The page shown when the login is cancelled or the provider returns an error.
+#[derive(Template)]
+#[template(path = "login_failed.html")]
+pub struct LoginFailedTemplate {
+    theme: Theme,
+    user: Option<User>,
+    csrf_token: String,
+    provider_name: String,
+    retry_url: String,
+    message: String,
+    description: Option<String>,
+}
+
+impl LoginFailedTemplate {
+    pub fn new(
+        theme: Theme,
+        user: Option<User>,
+        csrf_token: String,
+        provider_name: String,
+        retry_url: String,
+        message: String,
+        description: Option<String>,
+    ) -> Self {
+        LoginFailedTemplate {
+            theme,
+            user,
+            csrf_token,
+            provider_name,
+            retry_url,
+            message,
+            description,
+        }
+    }
+}
+
+impl IntoResponse for LoginFailedTemplate {
+    fn into_response(self) -> axum::response::Response {
+        match self.render() {
+            Ok(html) => Html(html).into_response(),
+            Err(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to render template: {err}"),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// The page asking to confirm the logout, the logout is only done with a form.
+#[derive(Template)]
+#[template(path = "logout.html")]
+pub struct LogoutTemplate {
+    theme: Theme,
+    user: Option<User>,
+    csrf_token: String,
+}
+
+impl LogoutTemplate {
+    pub fn new(theme: Theme, user: Option<User>, csrf_token: String) -> Self {
+        LogoutTemplate {
+            theme,
+            user,
+            csrf_token,
+        }
+    }
+}
+
+impl IntoResponse for LogoutTemplate {
+    fn into_response(self) -> axum::response::Response {
+        match self.render() {
+            Ok(html) => Html(html).into_response(),
+            Err(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to render template: {err}"),
+            )
+                .into_response(),
+        }
+    }
+}
+
 #[derive(Template)]
 #[template(path = "error.html")]
 struct ErrorTemplate {
     theme: Theme,
     user: Option<User>,
+    csrf_token: String,
     error: PageError,
 }
 
-async fn not_found(UserTheme(theme): UserTheme) -> ErrorTemplate {
+async fn not_found(UserTheme(theme): UserTheme, CsrfToken(csrf_token): CsrfToken) -> ErrorTemplate {
     let theme = theme.unwrap_or_default();
 
     ErrorTemplate {
         theme,
         user: None,
+        csrf_token,
         error: PageError {
             message: "Not Found".to_owned(),
             status: StatusCode::NOT_FOUND,
@@ -99,8 +430,9 @@ async fn not_found(UserTheme(theme): UserTheme) -> ErrorTemplate {
 
 impl IntoResponse for ErrorTemplate {
     fn into_response(self) -> axum::response::Response {
+        // The page keeps the status of the error, so the clients can tell the request failed
         match self.render() {
-            Ok(html) => Html(html).into_response(),
+            Ok(html) => (self.error.status, Html(html)).into_response(),
             Err(err) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("Failed to render template: {err}"),
@@ -112,12 +444,23 @@ impl IntoResponse for ErrorTemplate {
 
 pub async fn error_handler_middleware(
     UserTheme(theme): UserTheme,
+    CsrfToken(csrf_token): CsrfToken,
+    Extension(providers): Extension<ProviderRegistry>,
     request: Request,
     next: Next,
 ) -> axum::response::Response {
     let path = request.uri().path().to_string();
+    let method = request.method().clone();
+    // The API clients get the errors as they are, the browsers get an error page
+    let is_api_request = path.starts_with("/api/") && !accepts_html(request.headers());
+    let query = request.uri().query().map(|x| x.to_owned());
+    let login_query = Query::<LoginQuery>::try_from_uri(request.uri()).ok();
     let response = next.run(request).await;
 
+    if is_api_request {
+        return response;
+    }
+
     if response.status().is_client_error() || response.status().is_server_error() {
         let theme = theme.unwrap_or_default();
         let status = response.status();
@@ -128,21 +471,35 @@ pub async fn error_handler_middleware(
 
         if status == StatusCode::UNAUTHORIZED {
             if path == "/login" {
-                let html = LoginTemplate { theme, user: None }.render().unwrap();
+                let return_to = login_query
+                    .and_then(|Query(x)| x.return_to.as_deref().and_then(safe_redirect_target));
+
+                let html = LoginTemplate::new(theme, csrf_token, &providers, return_to)
+                    .render()
+                    .unwrap();
                 return Html(html).into_response();
+            } else if method == Method::GET {
+                // Send the user back to the requested page after login
+                let return_to = match query {
+                    Some(query) => format!("{path}?{query}"),
+                    None => path,
+                };
+
+                let return_to: String =
+                    form_urlencoded::byte_serialize(return_to.as_bytes()).collect();
+                return Redirect::to(&format!("/login?return_to={return_to}")).into_response();
             } else {
                 return Redirect::to("/login").into_response();
             }
         }
 
-        let html = ErrorTemplate {
+        return ErrorTemplate {
             theme,
             user: None,
+            csrf_token,
             error: PageError { status, message },
         }
-        .render()
-        .unwrap();
-        return Html(html).into_response();
+        .into_response();
     }
     response
 }
@@ -159,46 +516,3 @@ pub async fn auth_middleware(
         next.run(req).await
     }
 }
-
-mod filters {
-    pub fn take<T: std::fmt::Display>(
-        s: T,
-        _: &dyn askama::Values,
-        count: usize,
-    ) -> ::askama::Result<String> {
-        let s = s.to_string();
-        Ok(s[0..count].to_string())
-    }
-}
-
-use axum::{extract::Query, http::header, response::Response};
-use reqwest::Client;
-use std::collections::HashMap;
-
-pub async fn proxy_google_image(Query(params): Query<HashMap<String, String>>) -> Response {
-    let url = match params.get("url") {
-        Some(u) => u,
-        None => return (StatusCode::BAD_REQUEST, "Missing `url` param").into_response(),
-    };
-
-    let client = Client::new();
-    let Ok(resp) = client.get(url).send().await else {
-        return (StatusCode::BAD_GATEWAY, "Failed to fetch image").into_response();
-    };
-
-    let status = resp.status();
-    let content_type = resp
-        .headers()
-        .get(header::CONTENT_TYPE)
-        .cloned()
-        .unwrap_or_else(|| header::HeaderValue::from_static("image/jpeg"));
-
-    let bytes = match resp.bytes().await {
-        Ok(b) => b,
-        Err(_) => {
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read image").into_response();
-        }
-    };
-
-    (status, [(header::CONTENT_TYPE, content_type)], bytes).into_response()
-}
@@ -0,0 +1,122 @@
+use axum::body::Body;
+use axum::extract::{FromRequestParts, Request};
+use axum::http::request::Parts;
+use axum::http::{StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use oauth2::url::form_urlencoded;
+
+use super::PrivateCookies;
+use crate::constants::{COOKIE_CSRF_TOKEN, CSRF_FORM_FIELD, CSRF_HEADER, MAX_CSRF_FORM_SIZE};
+use crate::misc::random_hex_token;
+
+/// The CSRF token of the browser, the forms send it in the `csrf_token` field and other clients in
+/// the `X-CSRF-Token` header.
+///
+/// The token is stored in a private cookie, so it cannot be read or set by other sites.
+#[derive(Debug, Clone)]
+pub struct CsrfToken(pub String);
+
+impl<S> FromRequestParts<S> for CsrfToken
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<CsrfToken>().cloned().ok_or_else(|| {
+            tracing::error!("the csrf middleware is not set");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+    }
+}
+
+/// Makes the CSRF token of the browser available to the handlers, a new token is issued if the
+/// browser doesn't have one.
+pub async fn csrf_middleware(
+    cookies: PrivateCookies,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let current_token = cookies.get(COOKIE_CSRF_TOKEN).map(|x| x.value().to_owned());
+
+    let csrf_token = current_token.clone().unwrap_or_else(random_hex_token);
+    request
+        .extensions_mut()
+        .insert(CsrfToken(csrf_token.clone()));
+
+    let response = next.run(request).await;
+
+    if current_token.is_some() {
+        return response;
+    }
+
+    let csrf_cookie: Cookie = Cookie::build((COOKIE_CSRF_TOKEN, csrf_token))
+        .http_only(true)
+        .path("/")
+        .same_site(SameSite::Lax)
+        .into();
+
+    (cookies.jar().add(csrf_cookie), response).into_response()
+}
+
+/// Rejects the state-changing requests without the CSRF token of the browser.
+pub async fn verify_csrf(
+    CsrfToken(csrf_token): CsrfToken,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.method().is_safe() {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let header_token = parts
+        .headers
+        .get(CSRF_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_owned());
+
+    let is_form = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.starts_with("application/x-www-form-urlencoded"));
+
+    // The form is read to find the token and passed on to the handler
+    let (request_token, body) = match header_token {
+        Some(token) => (Some(token), body),
+        None if is_form => {
+            let Ok(bytes) = axum::body::to_bytes(body, MAX_CSRF_FORM_SIZE).await else {
+                return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+            };
+
+            let token = form_urlencoded::parse(&bytes)
+                .find(|(name, _)| name == CSRF_FORM_FIELD)
+                .map(|(_, value)| value.into_owned());
+
+            (token, Body::from(bytes))
+        }
+        None => (None, body),
+    };
+
+    let is_valid = request_token
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), csrf_token.as_bytes()));
+
+    if !is_valid {
+        tracing::warn!(
+            "invalid csrf token for {} {}",
+            parts.method,
+            parts.uri.path()
+        );
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    next.run(Request::from_parts(parts, body)).await
+}
+
+// Compares all the bytes so the time doesn't tell how much of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,14 +1,32 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::Context;
 use axum::Extension;
-use axum::extract::FromRequestParts;
-use axum::http::StatusCode;
+use axum::extract::{ConnectInfo, FromRequestParts, Request};
 use axum::http::request::Parts;
-use axum::response::IntoResponse;
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
 use axum_extra::extract::CookieJar;
+use axum_extra::extract::cookie::{Cookie, Key, PrivateCookieJar, SameSite};
+use chrono::NaiveDateTime;
 use sqlx::SqlitePool;
 
-use crate::constants::{COOKIE_AUTH_SESSION, COOKIE_THEME};
+use crate::constants::{
+    COOKIE_AUTH_SESSION, COOKIE_THEME, MAX_USER_AGENT_LENGTH, REMEMBER_ME_IDLE_TIMEOUT,
+    REMEMBER_ME_MAX_DAYS, REMEMBER_ME_MAX_LIFETIME, SESSION_EXTEND_INTERVAL, SESSION_IDLE_TIMEOUT,
+    SESSION_MAX_LIFETIME,
+};
 use crate::misc::Theme;
-use crate::models::User;
+use crate::models::{User, UserSession};
+
+mod csrf;
+mod session_cache;
+
+pub use csrf::{CsrfToken, csrf_middleware, verify_csrf};
+pub use session_cache::SessionCache;
 
 #[derive(Debug)]
 pub struct CurrentUser(pub User);
@@ -34,6 +52,12 @@ where
                 tracing::error!("{err}");
                 UnauthorizedUser
             })?;
+        let Extension(session_cache) = Extension::<SessionCache>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| {
+                tracing::error!("{err}");
+                UnauthorizedUser
+            })?;
         let cookies = CookieJar::from_request_parts(parts, state)
             .await
             .map_err(|err| {
@@ -45,15 +69,80 @@ where
             return Err(UnauthorizedUser);
         };
 
-        let user = crate::db::get_user_by_session_id(&pool, session_cookie.value())
+        let user_session = session_cache
+            .get(&pool, session_cookie.value())
             .await
             .map_err(|err| {
                 tracing::error!("failed to get current user: {err}");
                 UnauthorizedUser
             })?;
 
-        match user {
-            Some(x) => Ok(CurrentUser(x)),
+        match user_session {
+            Some((_, user)) => Ok(CurrentUser(user)),
+            None => Err(UnauthorizedUser),
+        }
+    }
+}
+
+impl<S> axum::extract::OptionalFromRequestParts<S> for CurrentUser
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        let user = <CurrentUser as FromRequestParts<S>>::from_request_parts(parts, state).await;
+        Ok(user.ok())
+    }
+}
+
+/// The session of the current user.
+#[derive(Debug)]
+pub struct CurrentSession(pub UserSession);
+
+impl<S> FromRequestParts<S> for CurrentSession
+where
+    S: Send + Sync,
+{
+    type Rejection = UnauthorizedUser;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Extension(pool) = Extension::<SqlitePool>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| {
+                tracing::error!("{err}");
+                UnauthorizedUser
+            })?;
+        let Extension(session_cache) = Extension::<SessionCache>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| {
+                tracing::error!("{err}");
+                UnauthorizedUser
+            })?;
+        let cookies = CookieJar::from_request_parts(parts, state)
+            .await
+            .map_err(|err| {
+                tracing::error!("{err}");
+                UnauthorizedUser
+            })?;
+
+        let Some(session_cookie) = cookies.get(COOKIE_AUTH_SESSION) else {
+            return Err(UnauthorizedUser);
+        };
+
+        let user_session = session_cache
+            .get(&pool, session_cookie.value())
+            .await
+            .map_err(|err| {
+                tracing::error!("failed to get current session: {err}");
+                UnauthorizedUser
+            })?;
+
+        match user_session {
+            Some((user_session, _)) => Ok(CurrentSession(user_session)),
             None => Err(UnauthorizedUser),
         }
     }
@@ -84,3 +173,400 @@ where
         }
     }
 }
+
+/// Keys used to encrypt and sign the private cookies.
+///
+/// The cookies are always encrypted with the current key, the previous keys are only used to
+/// read the cookies set before the key was rotated.
+#[derive(Clone)]
+pub struct CookieKeys {
+    current: Key,
+    previous: Vec<Key>,
+}
+
+impl CookieKeys {
+    /// Derives the keys from the `COOKIE_KEY` secret and the comma separated `COOKIE_KEY_PREVIOUS`
+    /// secrets, each secret must have at least 32 bytes.
+    ///
+    /// If `COOKIE_KEY` is not set a random key is used, so the private cookies are lost on restart.
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let current = match std::env::var("COOKIE_KEY").ok().filter(|x| !x.is_empty()) {
+            Some(secret) => derive_key(&secret).context("Invalid COOKIE_KEY")?,
+            None => {
+                tracing::warn!("COOKIE_KEY is not set, using a random cookie key");
+                Key::generate()
+            }
+        };
+
+        let previous = std::env::var("COOKIE_KEY_PREVIOUS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(derive_key)
+            .collect::<Result<Vec<_>, _>>()
+            .context("Invalid COOKIE_KEY_PREVIOUS")?;
+
+        Ok(CookieKeys { current, previous })
+    }
+
+    /// Returns an empty jar that encrypts the added cookies with the current key.
+    pub fn jar(&self) -> PrivateCookieJar {
+        PrivateCookieJar::new(self.current.clone())
+    }
+}
+
+fn derive_key(secret: &str) -> Result<Key, anyhow::Error> {
+    // Shorter secrets make the derivation panic
+    if secret.len() < 32 {
+        anyhow::bail!("The cookie key secret must have at least 32 bytes");
+    }
+
+    Ok(Key::derive_from(secret.as_bytes()))
+}
+
+/// The private cookies of the request, a cookie is only returned if it was encrypted with one of
+/// the `CookieKeys`.
+pub struct PrivateCookies {
+    keys: CookieKeys,
+    jars: Vec<PrivateCookieJar>,
+}
+
+impl PrivateCookies {
+    /// Returns the decrypted cookie with the given name.
+    pub fn get(&self, name: &str) -> Option<Cookie<'static>> {
+        self.jars.iter().find_map(|jar| jar.get(name))
+    }
+
+    /// Returns an empty jar that encrypts the added cookies with the current key.
+    pub fn jar(&self) -> PrivateCookieJar {
+        self.keys.jar()
+    }
+}
+
+impl<S> FromRequestParts<S> for PrivateCookies
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Extension(keys) = Extension::<CookieKeys>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| {
+                tracing::error!("{err}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+
+        let jars = std::iter::once(&keys.current)
+            .chain(&keys.previous)
+            .map(|key| PrivateCookieJar::from_headers(&parts.headers, key.clone()))
+            .collect();
+
+        Ok(PrivateCookies { keys, jars })
+    }
+}
+
+/// The session timeouts, the "remember me" sessions use longer timeouts that can be changed with
+/// the `REMEMBER_ME_IDLE_TIMEOUT_DAYS` and `REMEMBER_ME_MAX_LIFETIME_DAYS` environment variables.
+///
+/// The client IP recorded in the sessions is taken from the `X-Forwarded-For` header only if
+/// `TRUST_PROXY_HEADERS` is `true`, set it when running behind a reverse proxy.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionConfig {
+    remember_me_idle_timeout: Duration,
+    remember_me_max_lifetime: Duration,
+    trust_proxy_headers: bool,
+}
+
+impl SessionConfig {
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let get_days = |name: &str, default: Duration| match std::env::var(name) {
+            Ok(days) if !days.is_empty() => {
+                parse_days(&days).with_context(|| format!("Invalid {name}: {days}"))
+            }
+            _ => Ok(default),
+        };
+
+        let session_config = SessionConfig {
+            remember_me_idle_timeout: get_days(
+                "REMEMBER_ME_IDLE_TIMEOUT_DAYS",
+                REMEMBER_ME_IDLE_TIMEOUT,
+            )?,
+            remember_me_max_lifetime: get_days(
+                "REMEMBER_ME_MAX_LIFETIME_DAYS",
+                REMEMBER_ME_MAX_LIFETIME,
+            )?,
+            trust_proxy_headers: std::env::var("TRUST_PROXY_HEADERS")
+                .is_ok_and(|x| x.eq_ignore_ascii_case("true")),
+        };
+
+        if session_config.remember_me_idle_timeout > session_config.remember_me_max_lifetime {
+            anyhow::bail!(
+                "REMEMBER_ME_IDLE_TIMEOUT_DAYS cannot be greater than REMEMBER_ME_MAX_LIFETIME_DAYS"
+            );
+        }
+
+        Ok(session_config)
+    }
+
+    /// The time without activity after which the session expires.
+    pub fn idle_timeout(&self, remember_me: bool) -> Duration {
+        match remember_me {
+            true => self.remember_me_idle_timeout,
+            false => SESSION_IDLE_TIMEOUT,
+        }
+    }
+
+    /// The maximum time the session can be extended to since the login.
+    pub fn max_lifetime(&self, remember_me: bool) -> Duration {
+        match remember_me {
+            true => self.remember_me_max_lifetime,
+            false => SESSION_MAX_LIFETIME,
+        }
+    }
+}
+
+/// Parses a number of days, at most `REMEMBER_ME_MAX_DAYS` so the expiration dates stay in range.
+fn parse_days(days: &str) -> Result<Duration, anyhow::Error> {
+    let days = days.parse::<u64>()?;
+
+    if days > REMEMBER_ME_MAX_DAYS {
+        anyhow::bail!("The maximum is {REMEMBER_ME_MAX_DAYS} days");
+    }
+
+    let secs = days
+        .checked_mul(60 * 60 * 24)
+        .context("The number of days is too large")?;
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// The client that sent the request, recorded in the sessions so the user can recognize them.
+#[derive(Debug, Default, Clone)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.chars().take(MAX_USER_AGENT_LENGTH).collect());
+
+        let trust_proxy_headers = parts
+            .extensions
+            .get::<SessionConfig>()
+            .is_some_and(|x| x.trust_proxy_headers);
+
+        let forwarded_ip = parts
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .next_back()
+            .filter(|_| trust_proxy_headers)
+            .and_then(|x| x.to_str().ok())
+            .and_then(forwarded_ip);
+
+        let ip_address = forwarded_ip
+            .or_else(|| {
+                parts
+                    .extensions
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .map(|ConnectInfo(addr)| addr.ip())
+            })
+            .map(|x| x.to_string());
+
+        Ok(ClientInfo {
+            user_agent,
+            ip_address,
+        })
+    }
+}
+
+/// Returns the address added to `X-Forwarded-For` by the reverse proxy.
+///
+/// Each proxy appends the address it received the request from, so only the last one is set by
+/// our proxy, the others are sent by the client and can be anything.
+fn forwarded_ip(header: &str) -> Option<IpAddr> {
+    header.rsplit(',').next()?.trim().parse().ok()
+}
+
+/// Returns the session cookie with the given token.
+///
+/// The cookie of a "remember me" session expires with the session, otherwise the cookie is
+/// removed when the browser is closed.
+pub fn session_cookie(user_session: &UserSession, session_token: String) -> Cookie<'static> {
+    let mut cookie: Cookie = Cookie::build((COOKIE_AUTH_SESSION, session_token))
+        .same_site(SameSite::Lax)
+        .http_only(true)
+        .path("/")
+        .into();
+
+    if user_session.remember_me {
+        let max_age = user_session.expires_at - chrono::offset::Utc::now().naive_utc();
+        cookie.set_max_age(cookie::time::Duration::seconds(
+            max_age.num_seconds().max(0),
+        ));
+    }
+
+    cookie
+}
+
+/// Returns the new expiration of the session.
+///
+/// The session expires after the idle timeout without activity and never lives more than the max
+/// lifetime.
+fn extended_expiration(
+    user_session: &UserSession,
+    session_config: &SessionConfig,
+    now: NaiveDateTime,
+) -> Option<NaiveDateTime> {
+    let remember_me = user_session.remember_me;
+    let idle_timeout = chrono::Duration::from_std(session_config.idle_timeout(remember_me)).ok()?;
+    let max_lifetime = chrono::Duration::from_std(session_config.max_lifetime(remember_me)).ok()?;
+
+    Some((now + idle_timeout).min(user_session.created_at + max_lifetime))
+}
+
+/// Records the activity of the current session, extends its expiration and reissues the session
+/// cookie with the new expiration.
+///
+/// The session is only updated once every `SESSION_EXTEND_INTERVAL` so we don't write on each
+/// request.
+pub async fn session_middleware(
+    cookies: CookieJar,
+    client_info: ClientInfo,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(session_cache): Extension<SessionCache>,
+    Extension(session_config): Extension<SessionConfig>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(session_token) = cookies
+        .get(COOKIE_AUTH_SESSION)
+        .map(|x| x.value().to_owned())
+    else {
+        return next.run(request).await;
+    };
+
+    let user_session = session_cache
+        .get(&pool, &session_token)
+        .await
+        .inspect_err(|err| tracing::error!("failed to get user session: {err:#}"))
+        .ok()
+        .flatten()
+        .map(|(user_session, _)| user_session);
+
+    let mut response = next.run(request).await;
+
+    let Some(user_session) = user_session else {
+        return response;
+    };
+
+    let now = chrono::offset::Utc::now().naive_utc();
+    let Ok(extend_interval) = chrono::Duration::from_std(SESSION_EXTEND_INTERVAL) else {
+        return response;
+    };
+
+    if user_session
+        .last_seen_at
+        .is_some_and(|x| now - x < extend_interval)
+    {
+        return response;
+    }
+
+    let Some(expires_at) = extended_expiration(&user_session, &session_config, now) else {
+        return response;
+    };
+
+    // The session may have been removed or replaced by the request, like on logout or login
+    let sets_session_cookie = response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|x| Cookie::parse(x.to_str().ok()?).ok())
+        .any(|x| x.name() == COOKIE_AUTH_SESSION);
+
+    if sets_session_cookie {
+        return response;
+    }
+
+    let result = crate::db::touch_user_session(
+        &pool,
+        user_session.id,
+        expires_at,
+        client_info.user_agent,
+        client_info.ip_address,
+    )
+    .await;
+
+    // The cached session has the previous expiration
+    session_cache.invalidate_session(&session_token);
+
+    match result {
+        // Only the "remember me" cookies have an expiration to update
+        Ok(true) if user_session.remember_me && expires_at != user_session.expires_at => {
+            let user_session = UserSession {
+                expires_at,
+                ..user_session
+            };
+
+            let cookie = session_cookie(&user_session, session_token);
+            if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
+                response.headers_mut().append(header::SET_COOKIE, value);
+            }
+        }
+        Ok(_) => {}
+        Err(err) => tracing::error!("failed to update user session: {err:#}"),
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days_are_limited_so_expirations_stay_in_range() {
+        assert_eq!(
+            parse_days("30").unwrap(),
+            Duration::from_secs(30 * 24 * 60 * 60)
+        );
+        assert!(parse_days(&REMEMBER_ME_MAX_DAYS.to_string()).is_ok());
+        assert!(parse_days(&(REMEMBER_ME_MAX_DAYS + 1).to_string()).is_err());
+        assert!(parse_days("100000000").is_err());
+        assert!(parse_days(&u64::MAX.to_string()).is_err());
+        assert!(parse_days("-1").is_err());
+
+        let now = chrono::offset::Utc::now().naive_utc();
+        let max_days = parse_days(&REMEMBER_ME_MAX_DAYS.to_string()).unwrap();
+        assert!(
+            now.checked_add_signed(chrono::Duration::from_std(max_days).unwrap())
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn forwarded_ip_is_the_one_added_by_the_proxy() {
+        assert_eq!(
+            forwarded_ip("203.0.113.7"),
+            Some(IpAddr::from([203, 0, 113, 7]))
+        );
+        assert_eq!(
+            forwarded_ip("10.0.0.1, 198.51.100.2,203.0.113.7 "),
+            Some(IpAddr::from([203, 0, 113, 7]))
+        );
+        assert_eq!(forwarded_ip("203.0.113.7, not-an-ip"), None);
+        assert_eq!(forwarded_ip("::1").map(|x| x.is_loopback()), Some(true));
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::misc::lock_unpoisoned;
+use crate::models::{User, UserSession};
+
+/// In-memory cache of the session lookups, so the requests of signed in users don't query the
+/// database each time.
+///
+/// The entries are kept for at most `ttl` and never past the session expiration. The sessions
+/// removed or changed by this server must be invalidated, the changes made by other servers
+/// sharing the database are only seen once the entries expire.
+#[derive(Clone)]
+pub struct SessionCache {
+    state: Arc<Mutex<CacheState>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CachedSession>,
+    // Incremented on each invalidation, a session read from the database before an invalidation
+    // may already be removed, so it is not cached
+    generation: u64,
+}
+
+struct CachedSession {
+    user_session: UserSession,
+    user: User,
+    cached_at: Instant,
+}
+
+impl SessionCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        SessionCache {
+            state: Default::default(),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Returns the active session with the given token and its user, loading them from the
+    /// database if not cached.
+    pub async fn get(
+        &self,
+        pool: &SqlitePool,
+        session_token: &str,
+    ) -> Result<Option<(UserSession, User)>, anyhow::Error> {
+        let key = crate::db::hash_session_token(session_token);
+        if let Some(cached) = self.get_cached(&key) {
+            return Ok(Some(cached));
+        }
+
+        let generation = self.lock().generation;
+
+        let Some(user_session) = crate::db::get_user_session(pool, session_token).await? else {
+            return Ok(None);
+        };
+
+        let Some(user) = crate::db::get_user_by_id(pool, user_session.user_id).await? else {
+            return Ok(None);
+        };
+
+        self.insert(key, generation, user_session.clone(), user.clone());
+        Ok(Some((user_session, user)))
+    }
+
+    /// Removes the session with the given token.
+    pub fn invalidate_session(&self, session_token: &str) {
+        let key = crate::db::hash_session_token(session_token);
+        let mut state = self.lock();
+        state.generation += 1;
+        state.entries.remove(&key);
+    }
+
+    /// Removes all the sessions of the user, used when the user or its sessions change.
+    pub fn invalidate_user(&self, user_id: Uuid) {
+        let mut state = self.lock();
+        state.generation += 1;
+        state.entries.retain(|_, x| x.user.id != user_id);
+    }
+
+    fn get_cached(&self, key: &str) -> Option<(UserSession, User)> {
+        let mut state = self.lock();
+        let cached = state.entries.get(key)?;
+
+        if !self.is_fresh(cached, chrono::offset::Utc::now().naive_utc()) {
+            state.entries.remove(key);
+            return None;
+        }
+
+        Some((cached.user_session.clone(), cached.user.clone()))
+    }
+
+    /// Caches the session read from the database when the cache was at the given generation.
+    fn insert(&self, key: String, generation: u64, user_session: UserSession, user: User) {
+        let mut state = self.lock();
+        if state.generation != generation {
+            return;
+        }
+
+        let entries = &mut state.entries;
+
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let now = chrono::offset::Utc::now().naive_utc();
+            entries.retain(|_, x| self.is_fresh(x, now));
+
+            // Still full, make room by removing the oldest entry
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, x)| x.cached_at)
+                    .map(|(key, _)| key.clone());
+
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+
+        entries.insert(
+            key,
+            CachedSession {
+                user_session,
+                user,
+                cached_at: Instant::now(),
+            },
+        );
+    }
+
+    fn is_fresh(&self, cached: &CachedSession, now: chrono::NaiveDateTime) -> bool {
+        cached.cached_at.elapsed() < self.ttl && cached.user_session.expires_at > now
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        lock_unpoisoned(&self.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_session() -> (UserSession, User) {
+        let now = chrono::offset::Utc::now().naive_utc();
+        let user = User {
+            id: Uuid::new_v4(),
+            username: "test".to_owned(),
+            image_url: None,
+            email: None,
+            email_verified: false,
+            last_login_at: None,
+        };
+
+        let user_session = UserSession {
+            id: Uuid::new_v4(),
+            user_id: user.id,
+            remember_me: false,
+            user_agent: None,
+            ip_address: None,
+            created_at: now,
+            expires_at: now + chrono::Duration::hours(1),
+            last_seen_at: None,
+        };
+
+        (user_session, user)
+    }
+
+    #[test]
+    fn sessions_read_before_an_invalidation_are_not_cached() {
+        let cache = SessionCache::new(10, Duration::from_secs(60));
+        let (user_session, user) = user_session();
+
+        // A request reads the session while another one revokes it
+        let generation = cache.lock().generation;
+        cache.invalidate_user(user.id);
+        cache.insert(
+            "token".to_owned(),
+            generation,
+            user_session.clone(),
+            user.clone(),
+        );
+        assert!(cache.get_cached("token").is_none());
+
+        let generation = cache.lock().generation;
+        cache.insert("token".to_owned(), generation, user_session, user);
+        assert!(cache.get_cached("token").is_some());
+    }
+}
@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use sqlx::SqlitePool;
+use tokio::{sync::watch, task::JoinHandle, time::MissedTickBehavior};
+
+use crate::constants::PURGE_INTERVAL;
+
+/// Returns the interval of the purge task, set with the `PURGE_INTERVAL_MINUTES` environment
+/// variable.
+pub fn purge_interval_from_env() -> Result<Duration, anyhow::Error> {
+    let minutes = match std::env::var("PURGE_INTERVAL_MINUTES") {
+        Ok(minutes) if !minutes.is_empty() => minutes
+            .parse::<u64>()
+            .ok()
+            .filter(|x| *x > 0)
+            .with_context(|| format!("Invalid PURGE_INTERVAL_MINUTES: {minutes}"))?,
+        _ => return Ok(PURGE_INTERVAL),
+    };
+
+    Ok(Duration::from_secs(minutes * 60))
+}
+
+/// Starts a task that deletes the expired sessions and login flows of all the users on each
+/// interval, the task stops when `shutdown` changes.
+pub fn spawn_purge_task(
+    pool: SqlitePool,
+    interval: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => purge_expired(&pool).await,
+                _ = shutdown.changed() => break,
+            }
+        }
+
+        tracing::debug!("purge task stopped");
+    })
+}
+
+async fn purge_expired(pool: &SqlitePool) {
+    let sessions = crate::db::delete_all_expired_user_sessions(pool)
+        .await
+        .inspect_err(|err| tracing::error!("failed to purge expired sessions: {err:#}"))
+        .unwrap_or_default();
+
+    let oauth_flows = crate::db::delete_expired_oauth_flows(pool)
+        .await
+        .inspect_err(|err| tracing::error!("failed to purge expired oauth flows: {err:#}"))
+        .unwrap_or_default();
+
+    if sessions > 0 || oauth_flows > 0 {
+        tracing::info!("purged {sessions} expired sessions and {oauth_flows} expired oauth flows");
+    } else {
+        tracing::debug!("no expired sessions or oauth flows to purge");
+    }
+}